@@ -0,0 +1,245 @@
+//! Summarise and group rows while streaming, without keeping the whole file
+
+use crate::csv::dialect::Dialect;
+use crate::csv::easy::stream_valid_csv_with_lines;
+use crate::errors::{CsvError, Result};
+use std::io::BufRead;
+
+/// How the values of a column are combined into one number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregator {
+    /// The total of all values
+    Sum,
+    /// The arithmetic mean of all values
+    Mean,
+    /// The smallest value
+    Min,
+    /// The largest value
+    Max,
+    /// How many values there are
+    Count,
+}
+
+/// Combine the numbers in one column of valid CSV
+///
+/// Every row is read, so skip the header before passing the reader in, or set
+/// `skip_non_numeric`, which ignores any cell that isn't a number, along with rows
+/// too short to have the column. Otherwise such a cell is `CsvError::Invalid` at its
+/// line and column. Cells are trimmed before parsing, and `NaN` and infinities
+/// aren't numbers, so one stray cell can't swamp the result. With no values, the
+/// mean, minimum, and maximum are NaN, while the sum and count are zero.
+pub fn aggregate_column<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    column: usize,
+    aggregator: Aggregator,
+    skip_non_numeric: bool,
+) -> Result<f64> {
+    let mut count = 0usize;
+    let mut sum = 0.0;
+    let mut min = f64::NAN;
+    let mut max = f64::NAN;
+    for row in stream_valid_csv_with_lines(reader, dialect) {
+        let (lines, row) = row?;
+        let value = row
+            .get(column)
+            .and_then(|cell| cell.trim().parse::<f64>().ok())
+            .filter(|value| value.is_finite());
+        let Some(value) = value else {
+            if skip_non_numeric {
+                continue;
+            }
+            return Err(CsvError::invalid_at(
+                lines.start,
+                column,
+                "Cell is not a number",
+            ));
+        };
+        count += 1;
+        sum += value;
+        // These ignore NaN, so the first value replaces it
+        min = min.min(value);
+        max = max.max(value);
+    }
+    Ok(match aggregator {
+        Aggregator::Sum => sum,
+        Aggregator::Mean if count == 0 => f64::NAN,
+        Aggregator::Mean => sum / count as f64,
+        Aggregator::Min => min,
+        Aggregator::Max => max,
+        Aggregator::Count => count as f64,
+    })
+}
+
+/// Group the rows of valid CSV that is sorted by one column
+///
+/// Each group is a run of consecutive rows with the same key, the field in
+/// `column`, so only one group is held in memory at a time. Every row is read,
+/// so skip the header before passing the reader in. If `require_sorted` is set, a
+/// key that sorts before the one above it is `CsvError::Invalid`; otherwise it
+/// just starts a new group, even if that key was seen before. Keys are compared
+/// byte-wise as strings, so numbers must be zero-padded to sort: `10` comes before
+/// `9`. A row too short to have the key is an error too. Errors are positioned at
+/// the line the row starts on. The groups before an error are returned first, and
+/// nothing is returned after it.
+pub fn group_by_sorted_column<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    column: usize,
+    require_sorted: bool,
+) -> impl Iterator<Item = Result<(String, Vec<Vec<String>>)>> {
+    let mut rows = stream_valid_csv_with_lines(reader, dialect);
+    let mut read_keyed = move || {
+        Some(rows.next()?.and_then(|(lines, row)| match row.get(column) {
+            Some(key) => Ok((lines.start, key.clone(), row)),
+            None => Err(CsvError::invalid_at(
+                lines.start,
+                column,
+                "Row is too short to have the key column",
+            )),
+        }))
+    };
+    let mut pending = None;
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let (_, key, first) = match pending.take().or_else(&mut read_keyed)? {
+            Ok(keyed) => keyed,
+            Err(error) => {
+                failed = true;
+                return Some(Err(error));
+            }
+        };
+        let mut group = vec![first];
+        while let Some(next) = read_keyed() {
+            match next {
+                Ok((_, next_key, row)) if next_key == key => group.push(row),
+                Ok((line, next_key, _)) if require_sorted && next_key < key => {
+                    pending = Some(Err(CsvError::invalid_at(
+                        line,
+                        column,
+                        "Rows are not sorted by the key column",
+                    )));
+                    break;
+                }
+                next => {
+                    pending = Some(next);
+                    break;
+                }
+            }
+        }
+        Some(Ok((key, group)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_aggregate_column() {
+        let input = "id,price\n1, 2.5\n2,\"4\"\n3,-1\n";
+        let aggregate = |aggregator| {
+            let mut reader = Cursor::new(input);
+            reader.read_line(&mut String::new()).unwrap();
+            aggregate_column(reader, &Dialect::default(), 1, aggregator, false).unwrap()
+        };
+        assert_eq!(aggregate(Aggregator::Sum), 5.5);
+        assert_eq!(aggregate(Aggregator::Mean), 5.5 / 3.0);
+        assert_eq!(aggregate(Aggregator::Min), -1.0);
+        assert_eq!(aggregate(Aggregator::Max), 4.0);
+        assert_eq!(aggregate(Aggregator::Count), 3.0);
+    }
+
+    #[test]
+    fn test_aggregate_column_non_numeric() {
+        let input = "id,price\n1,2\n2,n/a\n3\n4,3\n";
+        let dialect = Dialect::default();
+        assert_eq!(
+            aggregate_column(Cursor::new(input), &dialect, 1, Aggregator::Sum, true),
+            Ok(5.0)
+        );
+        assert_eq!(
+            aggregate_column(Cursor::new(input), &dialect, 1, Aggregator::Sum, false),
+            Err(CsvError::invalid_at(0, 1, "Cell is not a number"))
+        );
+        let empty = aggregate_column(Cursor::new(""), &dialect, 0, Aggregator::Max, true);
+        assert!(empty.unwrap().is_nan());
+
+        let input = "1\nNaN\n inf\n-infinity\n2\n";
+        assert_eq!(
+            aggregate_column(Cursor::new(input), &dialect, 0, Aggregator::Sum, true),
+            Ok(3.0)
+        );
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        assert_eq!(
+            aggregate_column(
+                Cursor::new("# note\n1\nx\n"),
+                &dialect,
+                0,
+                Aggregator::Sum,
+                false
+            ),
+            Err(CsvError::invalid_at(2, 0, "Cell is not a number"))
+        );
+    }
+
+    #[test]
+    fn test_group_by_sorted_column() {
+        let input = "a,1\na,2\nb,3\nc,4\nc,5\n";
+        let groups = group_by_sorted_column(Cursor::new(input), &Dialect::default(), 0, true)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let summary = groups
+            .iter()
+            .map(|(key, rows)| (key.as_str(), rows.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![("a", 2), ("b", 1), ("c", 2)]);
+        assert_eq!(groups[2].1[1], vec!["c", "5"]);
+    }
+
+    #[test]
+    fn test_group_by_sorted_column_unsorted() {
+        let input = "a,1\nb,2\na,3\n";
+        let dialect = Dialect::default();
+        let groups =
+            group_by_sorted_column(Cursor::new(input), &dialect, 0, true).collect::<Vec<_>>();
+        assert_eq!(groups.len(), 3);
+        assert!(groups[1].is_ok());
+        assert_eq!(
+            groups[2],
+            Err(CsvError::invalid_at(
+                2,
+                0,
+                "Rows are not sorted by the key column"
+            ))
+        );
+        let keys = group_by_sorted_column(Cursor::new(input), &dialect, 0, false)
+            .map(|group| group.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["a", "b", "a"]);
+
+        // Keys sort byte-wise, and positions are physical lines
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let input = "# sorted\n9,\"x\ny\"\n10,z\n";
+        let groups =
+            group_by_sorted_column(Cursor::new(input), &dialect, 0, true).collect::<Vec<_>>();
+        assert_eq!(
+            groups[1],
+            Err(CsvError::invalid_at(
+                3,
+                0,
+                "Rows are not sorted by the key column"
+            ))
+        );
+    }
+}
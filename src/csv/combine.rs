@@ -0,0 +1,177 @@
+//! Combine several parsed CSV files into one
+
+use crate::errors::{CsvError, Result};
+use std::collections::HashMap;
+
+/// Merge files with overlapping headers into one table
+///
+/// The merged header is every column name in the order it is first seen. Each
+/// file's rows are reordered to match it, and columns a file doesn't have are
+/// left empty. Columns are matched by name only, so a name repeated within one
+/// header is an error, as is a row that doesn't match the width of its header.
+pub fn merge_aligned(
+    headers_and_rows: Vec<(Vec<String>, Vec<Vec<String>>)>,
+) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let mut merged_header: Vec<String> = Vec::new();
+    let mut column_indices: HashMap<String, usize> = HashMap::new();
+    for (header, _) in &headers_and_rows {
+        for name in header {
+            if !column_indices.contains_key(name) {
+                column_indices.insert(name.clone(), merged_header.len());
+                merged_header.push(name.clone());
+            }
+        }
+    }
+
+    let mut merged_rows = Vec::new();
+    for (header, rows) in headers_and_rows {
+        let mut targets = Vec::with_capacity(header.len());
+        for (column, name) in header.iter().enumerate() {
+            if header[..column].contains(name) {
+                return Err(CsvError::invalid_at(
+                    0,
+                    column,
+                    "Duplicate column name in header",
+                ));
+            }
+            targets.push(column_indices[name]);
+        }
+        for (row_index, row) in rows.into_iter().enumerate() {
+            if row.len() != header.len() {
+                return Err(CsvError::invalid_at(
+                    row_index + 1,
+                    header.len(),
+                    "Row does not have the same number of fields as its header",
+                ));
+            }
+            let mut merged_row = vec![String::new(); merged_header.len()];
+            for (field, &target) in row.into_iter().zip(targets.iter()) {
+                merged_row[target] = field;
+            }
+            merged_rows.push(merged_row);
+        }
+    }
+    Ok((merged_header, merged_rows))
+}
+
+/// Pair up the rows of two files, in order
+///
+/// Both iterators advance together. The first error from either is passed on and
+/// ends the iteration, as does one file running out of rows before the other,
+/// which is reported at the first line the shorter file doesn't have.
+pub fn zip_rows<A, B>(a: A, b: B) -> impl Iterator<Item = Result<(Vec<String>, Vec<String>)>>
+where
+    A: Iterator<Item = Result<Vec<String>>>,
+    B: Iterator<Item = Result<Vec<String>>>,
+{
+    let mut a = a.fuse();
+    let mut b = b.fuse();
+    let mut line = 0;
+    let mut finished = false;
+    std::iter::from_fn(move || {
+        if finished {
+            return None;
+        }
+        let pair = match (a.next(), b.next()) {
+            (None, None) => None,
+            (Some(Err(error)), _) | (_, Some(Err(error))) => Some(Err(error)),
+            (Some(Ok(left)), Some(Ok(right))) => Some(Ok((left, right))),
+            (Some(Ok(_)), None) | (None, Some(Ok(_))) => Some(Err(CsvError::invalid_at(
+                line,
+                0,
+                "Files have different numbers of rows",
+            ))),
+        };
+        finished = !matches!(pair, Some(Ok(_)));
+        line += 1;
+        pair
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::easy::fast_stream_valid_csv;
+    use crate::Position;
+    use std::io::Cursor;
+
+    fn strings(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|field| field.to_string()).collect()
+    }
+
+    #[test]
+    fn test_merge_aligned() {
+        let first = (
+            strings(&["id", "name"]),
+            vec![strings(&["1", "Ada"]), strings(&["2", "Bo"])],
+        );
+        let second = (
+            strings(&["name", "email", "id"]),
+            vec![strings(&["Cy", "cy@example.com", "3"])],
+        );
+        let (header, rows) = merge_aligned(vec![first, second]).unwrap();
+        assert_eq!(header, strings(&["id", "name", "email"]));
+        assert_eq!(
+            rows,
+            vec![
+                strings(&["1", "Ada", ""]),
+                strings(&["2", "Bo", ""]),
+                strings(&["3", "Cy", "cy@example.com"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_aligned_errors() {
+        let duplicate = (strings(&["id", "id"]), vec![]);
+        assert_eq!(
+            merge_aligned(vec![duplicate]).unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 0, column: 1 },
+                "Duplicate column name in header"
+            )
+        );
+        let ragged = (strings(&["id", "name"]), vec![strings(&["1"])]);
+        assert_eq!(
+            merge_aligned(vec![ragged]).unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 1, column: 2 },
+                "Row does not have the same number of fields as its header"
+            )
+        );
+    }
+
+    #[test]
+    fn test_zip_rows() {
+        let a = fast_stream_valid_csv(Cursor::new("1,Ada\n2,Bo\n"), ',', '"');
+        let b = fast_stream_valid_csv(
+            Cursor::new("1,ada@example.com\n2,bo@example.com\n"),
+            ',',
+            '"',
+        );
+        let pairs = zip_rows(a, b).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                (strings(&["1", "Ada"]), strings(&["1", "ada@example.com"])),
+                (strings(&["2", "Bo"]), strings(&["2", "bo@example.com"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zip_rows_length_mismatch() {
+        let a = fast_stream_valid_csv(Cursor::new("1\n2\n"), ',', '"');
+        let b = fast_stream_valid_csv(Cursor::new("1\n"), ',', '"');
+        let mut pairs = zip_rows(a, b);
+        assert!(pairs.next().unwrap().is_ok());
+        assert_eq!(
+            pairs.next().unwrap().unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 1, column: 0 },
+                "Files have different numbers of rows"
+            )
+        );
+        assert!(pairs.next().is_none());
+    }
+}
@@ -0,0 +1,328 @@
+//! Convert parsed CSV into other formats and data structures
+
+use crate::csv::dialect::Dialect;
+use crate::csv::easy::{
+    dedupe_header, fast_stream_valid_csv, stream_valid_csv_with_lines, DuplicateHeaderPolicy,
+};
+use crate::errors::{CsvError, Result};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+
+/// Convert valid CSV into TSV, one line at a time.
+///
+/// The output is never quoted. Instead, tabs, newlines, carriage returns and
+/// backslashes inside fields are written as C-style escapes (`\t`, `\n`, `\r`, `\\`)
+/// so every record is exactly one line and every tab is a field separator.
+pub fn csv_to_tsv<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    delimiter: char,
+    quote: char,
+) -> Result<()> {
+    for row in fast_stream_valid_csv(reader, delimiter, quote) {
+        let row = row?;
+        for (index, field) in row.iter().enumerate() {
+            if index > 0 {
+                writer.write_all(b"\t")?;
+            }
+            writer.write_all(escape_tsv_field(field).as_bytes())?;
+        }
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Convert valid CSV with a header row into newline-delimited JSON.
+///
+/// Each row after the header becomes one JSON object mapping header names to
+/// string values. Keys are always written in sorted order and strings are always
+/// escaped the same way, so the same input gives byte-for-byte the same output on
+/// every run and platform, which makes the output safe to compare against golden
+/// files. Rows must have as many fields as the header, and a name repeated in the
+/// header is `CsvError::Invalid`, since one of its values would be lost.
+pub fn csv_to_ndjson<R: BufRead, W: Write>(
+    reader: R,
+    mut writer: W,
+    dialect: &Dialect,
+) -> Result<()> {
+    let mut rows = stream_valid_csv_with_lines(reader, dialect);
+    let Some((header_lines, header)) = rows.next().transpose()? else {
+        return Ok(());
+    };
+    let header =
+        dedupe_header(header, DuplicateHeaderPolicy::Error).map_err(|error| match error {
+            CsvError::Invalid(position, message) => {
+                CsvError::invalid_at(header_lines.start, position.column, message)
+            }
+            error => error,
+        })?;
+    for row in rows {
+        let (lines, row) = row?;
+        if row.len() != header.len() {
+            return Err(CsvError::invalid_at(
+                lines.start,
+                header.len(),
+                "Row does not have the same number of fields as the header",
+            ));
+        }
+        let record = header.iter().zip(row.iter()).collect::<BTreeMap<_, _>>();
+        writer.write_all(b"{")?;
+        for (index, (key, value)) in record.into_iter().enumerate() {
+            if index > 0 {
+                writer.write_all(b",")?;
+            }
+            write_json_string(&mut writer, key)?;
+            writer.write_all(b":")?;
+            write_json_string(&mut writer, value)?;
+        }
+        writer.write_all(b"}\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a JSON string literal, escaping only what JSON requires
+fn write_json_string<W: Write>(writer: &mut W, text: &str) -> Result<()> {
+    writer.write_all(b"\"")?;
+    for ch in text.chars() {
+        match ch {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            _ if (ch as u32) < 0x20 => {
+                write!(writer, "\\u{:04x}", ch as u32)?;
+            }
+            _ => {
+                let mut buffer = [0; 4];
+                writer.write_all(ch.encode_utf8(&mut buffer).as_bytes())?;
+            }
+        }
+    }
+    writer.write_all(b"\"")?;
+    Ok(())
+}
+
+/// What to do when a key appears more than once in a key-value file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    /// Fail with `CsvError::Invalid` at the repeated key
+    Error,
+    /// Keep the value from the first row with the key
+    KeepFirst,
+    /// Keep the value from the last row with the key
+    KeepLast,
+}
+
+/// Read a file of `key,value` rows into a map
+///
+/// Rows are read as in [`stream_valid_csv_with_lines`], so the dialect's comments
+/// and empty lines are skipped. Every row must have exactly two fields, and errors
+/// are reported at the start of the line the row starts on.
+pub fn parse_key_value<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    duplicates: DuplicateKeyPolicy,
+) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for row in stream_valid_csv_with_lines(reader, dialect) {
+        let (lines, row) = row?;
+        let line = lines.start;
+        let [key, value]: [String; 2] = row.try_into().map_err(|_| {
+            CsvError::invalid_at(line, 0, "Key-value rows must have exactly two fields")
+        })?;
+        match (map.entry(key), duplicates) {
+            (Entry::Vacant(entry), _) => {
+                entry.insert(value);
+            }
+            (Entry::Occupied(_), DuplicateKeyPolicy::Error) => {
+                return Err(CsvError::invalid_at(line, 0, "Duplicate key"));
+            }
+            (Entry::Occupied(_), DuplicateKeyPolicy::KeepFirst) => {}
+            (Entry::Occupied(mut entry), DuplicateKeyPolicy::KeepLast) => {
+                entry.insert(value);
+            }
+        }
+    }
+    Ok(map)
+}
+
+/// Rewrite every record's line ending as `\n`, streaming
+///
+/// Both `\r\n` and a lone `\r` become `\n`, but only between records: inside
+/// a double-quoted field, `\r` and `\n` are part of the value and are copied
+/// as-is. Nothing else is checked, so this works on CSV too broken to parse.
+pub fn normalize_line_endings<R: BufRead, W: Write>(mut reader: R, mut writer: W) -> Result<()> {
+    let mut quoted = false;
+    // A `\r` was just rewritten, so a `\n` right after it is dropped
+    let mut after_cr = false;
+    let mut output = Vec::new();
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            break;
+        }
+        output.clear();
+        for &byte in buffer {
+            if std::mem::take(&mut after_cr) && byte == b'\n' {
+                continue;
+            }
+            match byte {
+                b'"' => quoted = !quoted,
+                b'\r' if !quoted => {
+                    after_cr = true;
+                    output.push(b'\n');
+                    continue;
+                }
+                _ => {}
+            }
+            output.push(byte);
+        }
+        writer.write_all(&output)?;
+        let length = buffer.len();
+        reader.consume(length);
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Escape the characters that would break a TSV field
+fn escape_tsv_field(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for ch in field.chars() {
+        match ch {
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\\' => escaped.push_str("\\\\"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Position;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_csv_to_tsv() {
+        let input = Cursor::new("a,b,c\n1,\"x\ty\",\"3,4\"\n");
+        let mut output = Vec::new();
+        csv_to_tsv(input, &mut output, ',', '"').unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "a\tb\tc\n1\tx\\ty\t3,4\n"
+        );
+    }
+
+    #[test]
+    fn test_escape_tsv_field() {
+        assert_eq!(escape_tsv_field("tab\there"), "tab\\there");
+        assert_eq!(escape_tsv_field("line\nbreak\r"), "line\\nbreak\\r");
+        assert_eq!(escape_tsv_field("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn test_parse_key_value() {
+        let dialect = Dialect::default();
+        let input = Cursor::new("host,localhost\nport,8080\nname,\"a, b\"\n");
+        let map = parse_key_value(input, &dialect, DuplicateKeyPolicy::Error).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map["host"], "localhost");
+        assert_eq!(map["port"], "8080");
+        assert_eq!(map["name"], "a, b");
+
+        let input = Cursor::new("a,1\nb,2\na,3\n");
+        assert_eq!(
+            parse_key_value(input, &dialect, DuplicateKeyPolicy::Error).unwrap_err(),
+            CsvError::Invalid(Position { line: 2, column: 0 }, "Duplicate key")
+        );
+        let input = Cursor::new("a,1\nb,2\na,3\n");
+        let map = parse_key_value(input, &dialect, DuplicateKeyPolicy::KeepLast).unwrap();
+        assert_eq!(map["a"], "3");
+
+        let input = Cursor::new("a,1\nb,2,3\n");
+        assert_eq!(
+            parse_key_value(input, &dialect, DuplicateKeyPolicy::Error).unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 1, column: 0 },
+                "Key-value rows must have exactly two fields"
+            )
+        );
+
+        let dialect = Dialect {
+            comment: Some('#'),
+            skip_empty_lines: true,
+            ..Dialect::default()
+        };
+        let input = Cursor::new("# settings\n\na,\"1\n2\"\nb,2,3\n");
+        assert_eq!(
+            parse_key_value(input, &dialect, DuplicateKeyPolicy::Error).unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 4, column: 0 },
+                "Key-value rows must have exactly two fields"
+            )
+        );
+    }
+
+    #[test]
+    fn test_csv_to_ndjson() {
+        let input = "name,id,note\nAda,1,\"says \"\"hi\"\"\"\nBo,2,tab\there\u{1}\n";
+        let mut first = Vec::new();
+        csv_to_ndjson(Cursor::new(input), &mut first, &Dialect::default()).unwrap();
+        let mut second = Vec::new();
+        csv_to_ndjson(Cursor::new(input), &mut second, &Dialect::default()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(
+            String::from_utf8(first).unwrap(),
+            concat!(
+                "{\"id\":\"1\",\"name\":\"Ada\",\"note\":\"says \\\"hi\\\"\"}\n",
+                "{\"id\":\"2\",\"name\":\"Bo\",\"note\":\"tab\\there\\u0001\"}\n",
+            )
+        );
+
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let input = Cursor::new("# names\na,a\n1,2\n");
+        assert_eq!(
+            csv_to_ndjson(input, Vec::new(), &dialect).unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 1, column: 1 },
+                "Duplicate column name in header"
+            )
+        );
+        let input = Cursor::new("a,b\n1,\"x\ny\"\n2\n");
+        assert_eq!(
+            csv_to_ndjson(input, Vec::new(), &dialect).unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 3, column: 2 },
+                "Row does not have the same number of fields as the header"
+            )
+        );
+    }
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let input = b"id,note\r\n1,\"two\r\nlines\"\r2,\"say \"\"hi\"\"\"\n3,\"\r\"\r\n";
+        let expected = b"id,note\n1,\"two\r\nlines\"\n2,\"say \"\"hi\"\"\"\n3,\"\r\"\n";
+        let mut output = Vec::new();
+        normalize_line_endings(&input[..], &mut output).unwrap();
+        assert_eq!(output, expected);
+        // Splitting `\r\n` across two reads still makes one `\n`
+        let mut output = Vec::new();
+        normalize_line_endings(
+            std::io::BufReader::with_capacity(1, &input[..]),
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,605 @@
+//! Heuristics for detecting the dialect of an unknown CSV file
+//!
+//! These work on a sample of the raw bytes and never fail on it; when the
+//! evidence is too weak to decide they return `None` instead of guessing.
+
+use crate::csv::dialect::Dialect;
+use crate::csv::easy::{dialect_byte, fast_stream_valid_csv, stream_valid_csv_with_lines};
+use crate::csv::medium::{CharacterClass, ColumnComplexity};
+use crate::errors::Result;
+use crate::Position;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+/// How many rows after the first are sampled when looking for a header
+const HEADER_SAMPLE_ROWS: usize = 20;
+
+/// The delimiters tried by [`probe_delimiter_and_quote`], most common first
+const PROBE_DELIMITERS: &[u8] = b",;\t|";
+
+/// How many rows are sampled when guessing the quoting policy
+const QUOTE_POLICY_SAMPLE_ROWS: usize = 100;
+
+/// How many rows are sampled when looking for a column with unescaped delimiters
+const INVALID_COLUMN_SAMPLE_ROWS: usize = 1000;
+
+/// A text encoding that can be identified by its byte order mark
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8, with the optional mark Excel likes to write
+    Utf8,
+    /// UTF-16, least significant byte first
+    Utf16Le,
+    /// UTF-16, most significant byte first
+    Utf16Be,
+}
+impl Encoding {
+    /// The byte order mark that starts a file in this encoding
+    pub fn bom(&self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"\xEF\xBB\xBF",
+            Encoding::Utf16Le => b"\xFF\xFE",
+            Encoding::Utf16Be => b"\xFE\xFF",
+        }
+    }
+
+    /// How many bytes to skip to get past the byte order mark
+    pub fn bom_len(&self) -> usize {
+        self.bom().len()
+    }
+}
+
+/// Identify the encoding of a file from its byte order mark, if it has one
+///
+/// Everything in this crate parses UTF-8, so a UTF-16 file needs transcoding
+/// first; the mark's length says where the text starts.
+pub fn detect_bom(raw: &[u8]) -> Option<Encoding> {
+    [Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be]
+        .into_iter()
+        .find(|encoding| raw.starts_with(encoding.bom()))
+}
+
+/// Pick the most plausible delimiter from a list of candidates
+///
+/// Each candidate is counted on every line of the sample, ignoring anything
+/// between double quotes. The winner is the candidate whose most common count
+/// per line is shared by the most lines, preferring more columns on a tie.
+/// Candidates that never appear outside of quotes are never chosen.
+pub fn best_delimiter(raw: &[u8], candidates: &[u8]) -> Option<u8> {
+    candidates
+        .iter()
+        .filter_map(|&candidate| {
+            let counts = delimiter_counts(raw, candidate, Some(b'"'));
+            let (modal_count, consistent_lines) = modal_count(&counts)?;
+            (modal_count > 0).then_some((consistent_lines, modal_count, candidate))
+        })
+        // Earlier candidates win ties, so reverse before taking the maximum
+        .rev()
+        .max_by_key(|&(consistent_lines, modal_count, _)| (consistent_lines, modal_count))
+        .map(|(_, _, candidate)| candidate)
+}
+
+/// Pick the most plausible delimiter and quote together
+///
+/// Every pair of a common delimiter and quote, or no quote, is scored like
+/// [`best_delimiter`], counting delimiters outside that pair's quotes. Between
+/// equally consistent pairs, one whose quotes evidently wrap fields beats one
+/// without quotes, and then more columns win. A quote is evident if it opens
+/// fields just after a delimiter or line break and closes the same number just
+/// before one. Returns `None` if no delimiter appears outside quotes.
+pub fn probe_delimiter_and_quote(sample: &[u8]) -> Option<(u8, Option<u8>)> {
+    let mut best = None;
+    let mut best_score = None;
+    for &delimiter in PROBE_DELIMITERS {
+        for quote in [Some(b'"'), Some(b'\''), None] {
+            let evident = match quote {
+                Some(quote) => match quote_balance(sample, delimiter, quote) {
+                    Some(true) => true,
+                    // Unbalanced quotes rule the pair out, and absent ones are
+                    // left to the pair with no quote
+                    _ => continue,
+                },
+                None => false,
+            };
+            let counts = delimiter_counts(sample, delimiter, quote);
+            let Some((modal_count, consistent_lines)) = modal_count(&counts) else {
+                continue;
+            };
+            let score = (consistent_lines, evident, modal_count);
+            if modal_count > 0 && best_score.is_none_or(|best_score| score > best_score) {
+                best = Some((delimiter, quote));
+                best_score = Some(score);
+            }
+        }
+    }
+    best
+}
+
+/// Whether quotes wrap fields: `None` if there are none at field boundaries,
+/// otherwise whether as many close fields as open them
+fn quote_balance(sample: &[u8], delimiter: u8, quote: u8) -> Option<bool> {
+    let is_boundary = |byte: Option<&u8>| {
+        byte.is_none_or(|&byte| byte == delimiter || byte == b'\n' || byte == b'\r')
+    };
+    let mut opening = 0;
+    let mut closing = 0;
+    for (index, _) in sample
+        .iter()
+        .enumerate()
+        .filter(|&(_, &byte)| byte == quote)
+    {
+        let before = index.checked_sub(1).and_then(|before| sample.get(before));
+        if is_boundary(before) {
+            opening += 1;
+        }
+        if is_boundary(sample.get(index + 1)) {
+            closing += 1;
+        }
+    }
+    (opening > 0).then_some(opening == closing)
+}
+
+/// Estimate how many records a file has from a sample of its start
+///
+/// The average length of the complete records in the sample, counting newlines
+/// inside quotes as part of their record, is extrapolated to `total_bytes`. A
+/// sample without a single complete record is taken to be one record long. An
+/// empty sample gives no evidence, so the estimate is zero. A quote that isn't
+/// ASCII can't be found in the bytes, so it is `CsvError::Invalid`.
+pub fn estimate_row_count(sample: &[u8], total_bytes: u64, dialect: &Dialect) -> Result<u64> {
+    let quote = dialect_byte(dialect.quote)?;
+    let mut records = 0u64;
+    let mut record_bytes = 0;
+    let mut within_quotes = false;
+    for (index, &byte) in sample.iter().enumerate() {
+        if byte == quote {
+            within_quotes = !within_quotes;
+        } else if byte == b'\n' && !within_quotes {
+            records += 1;
+            record_bytes = index + 1;
+        }
+    }
+    if records == 0 {
+        records = 1;
+        record_bytes = sample.len();
+    }
+    if record_bytes == 0 {
+        return Ok(0);
+    }
+    Ok((total_bytes as f64 * records as f64 / record_bytes as f64).round() as u64)
+}
+
+/// Count the occurrences of a delimiter on each non-empty line, outside of quotes
+fn delimiter_counts(raw: &[u8], delimiter: u8, quote: Option<u8>) -> Vec<usize> {
+    let mut counts = Vec::new();
+    let mut count = 0;
+    let mut line_length = 0;
+    let mut within_quotes = false;
+    for &byte in raw {
+        if Some(byte) == quote {
+            within_quotes = !within_quotes;
+        } else if byte == b'\n' && !within_quotes {
+            if line_length > 0 {
+                counts.push(count);
+            }
+            count = 0;
+            line_length = 0;
+            continue;
+        } else if byte == delimiter && !within_quotes {
+            count += 1;
+        }
+        line_length += 1;
+    }
+    if line_length > 0 {
+        counts.push(count);
+    }
+    counts
+}
+
+/// Find the most common count and how many lines share it
+fn modal_count(counts: &[usize]) -> Option<(usize, usize)> {
+    let mut sorted = counts.to_vec();
+    sorted.sort_unstable();
+    sorted
+        .chunk_by(|a, b| a == b)
+        .map(|run| (run[0], run.len()))
+        .max_by_key(|&(count, lines)| (lines, count))
+}
+
+/// Guess whether the first row of a file is a header
+///
+/// Each column votes by comparing the character class of its first field to the
+/// most common class of the fields below it. A column of numbers under a label
+/// votes for a header, while a column whose first field looks like the rest votes
+/// against. Files with no rows after the first are assumed to have no header.
+pub fn detect_header<R: BufRead>(reader: R, dialect: &Dialect) -> bool {
+    let mut rows = fast_stream_valid_csv(reader, dialect.delimiter, dialect.quote)
+        .map_while(|row| row.ok())
+        .take(HEADER_SAMPLE_ROWS + 1);
+    let Some(first_row) = rows.next() else {
+        return false;
+    };
+    let mut body_classes = vec![BTreeMap::new(); first_row.len()];
+    for row in rows {
+        for (class_counts, field) in body_classes.iter_mut().zip(row.iter()) {
+            if let Some(class) = dominant_class(field) {
+                *class_counts.entry(class).or_insert(0) += 1;
+            }
+        }
+    }
+    let mut votes = 0i64;
+    for (field, class_counts) in first_row.iter().zip(body_classes) {
+        let body_class = class_counts
+            .into_iter()
+            .max_by_key(|&(class, count)| (count, Reverse(class)))
+            .map(|(class, _)| class);
+        match (dominant_class(field), body_class) {
+            (_, None) => {}
+            (header_class, body_class) if header_class == body_class => votes -= 1,
+            _ => votes += 1,
+        }
+    }
+    votes > 0
+}
+
+/// Find the lines where the structure of a file changes
+///
+/// The most common column count is tracked over a sliding window of `window` rows,
+/// and every time it shifts, the position of the first row in the window with the
+/// new count is reported. This flags seams between concatenated files while
+/// ignoring the odd malformed row. Ties keep the previous count, so a change has to
+/// win a strict majority of the window before it is reported. Rows are read as in
+/// [`stream_valid_csv_with_lines`], and each change is at the line its row starts on.
+pub fn column_count_changes<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    window: usize,
+) -> Vec<Position> {
+    let (starts, counts): (Vec<_>, Vec<_>) = stream_valid_csv_with_lines(reader, dialect)
+        .map_while(|row| row.ok())
+        .map(|(lines, row)| (lines.start, row.len()))
+        .unzip();
+    let window = window.max(1);
+    let mut changes = Vec::new();
+    let mut previous_mode = None;
+    for (end, rows) in counts.windows(window).enumerate() {
+        let mode = window_mode(rows, previous_mode);
+        if previous_mode.is_some_and(|previous| previous != mode) {
+            let offset = rows.iter().position(|&count| count == mode).unwrap_or(0);
+            changes.push(Position {
+                line: starts[end + offset],
+                column: 0,
+            });
+        }
+        previous_mode = Some(mode);
+    }
+    changes
+}
+
+/// When the writer of a file chose to quote a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotePolicy {
+    /// Every field is quoted, whether it needs it or not
+    Always,
+    /// Only fields containing delimiters, quotes or newlines are quoted
+    Minimal,
+}
+
+/// Guess whether a file quotes every field or only the ones that need it
+///
+/// The fields of the first rows are counted, and if at least nine in ten of them
+/// start with a quote the file is taken to quote everything. A stray unquoted
+/// field, such as a hand-edited one, doesn't change the verdict.
+pub fn detect_quote_policy<R: BufRead>(reader: R, dialect: &Dialect) -> QuotePolicy {
+    let mut fields = 0;
+    let mut quoted_fields = 0;
+    for line in reader
+        .lines()
+        .map_while(|line| line.ok())
+        .take(QUOTE_POLICY_SAMPLE_ROWS)
+    {
+        let mut within_quotes = false;
+        let mut field_start = true;
+        for ch in line.chars() {
+            if field_start {
+                fields += 1;
+                if ch == dialect.quote {
+                    quoted_fields += 1;
+                }
+            }
+            field_start = false;
+            if ch == dialect.quote {
+                within_quotes = !within_quotes;
+            } else if ch == dialect.delimiter && !within_quotes {
+                field_start = true;
+            }
+        }
+        // An empty last field, after a trailing delimiter or on an empty line
+        if field_start {
+            fields += 1;
+        }
+    }
+    if fields > 0 && quoted_fields * 10 >= fields * 9 {
+        QuotePolicy::Always
+    } else {
+        QuotePolicy::Minimal
+    }
+}
+
+/// The most common count in a window, preferring `previous` on a tie
+fn window_mode(rows: &[usize], previous: Option<usize>) -> usize {
+    let mut frequencies = BTreeMap::new();
+    for &count in rows {
+        *frequencies.entry(count).or_insert(0) += 1;
+    }
+    frequencies
+        .into_iter()
+        .max_by_key(|&(count, frequency)| (frequency, Some(count) == previous))
+        .map(|(count, _)| count)
+        .unwrap_or(0)
+}
+
+/// Suggest which column most likely contains unescaped delimiters
+///
+/// Only rows with the expected number of columns are sampled, since those are the
+/// ones that split correctly. The column with the highest gini impurity, that is
+/// the one that looks most like free text, is the most likely to have stray
+/// delimiters in the rows that didn't. This is a good guess for the
+/// `invalid_column_index` of `fast_stream_csv_with_unescaped_delimiters`.
+pub fn suggest_invalid_column<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    expected_columns: usize,
+) -> Option<usize> {
+    let mut complexities = vec![ColumnComplexity::default(); expected_columns];
+    let mut sampled_any = false;
+    for row in fast_stream_valid_csv(reader, dialect.delimiter, dialect.quote)
+        .map_while(|row| row.ok())
+        .filter(|row| row.len() == expected_columns)
+        .take(INVALID_COLUMN_SAMPLE_ROWS)
+    {
+        sampled_any = true;
+        for (complexity, field) in complexities.iter_mut().zip(row.iter()) {
+            complexity.add_bytes(field.as_bytes());
+        }
+    }
+    if !sampled_any {
+        return None;
+    }
+    complexities
+        .iter()
+        .map(ColumnComplexity::gini_impurity)
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(column, _)| column)
+}
+
+/// What a row of a file holds, as far as the heuristics can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowKind {
+    /// Column labels, either the first row or a repeat of it
+    Header,
+    /// Decoration between rows, like `---,---,---`
+    Separator,
+    /// Anything else
+    Data,
+}
+
+/// Classify every row as data, a separator, or a repeated header
+///
+/// The first row is taken as the header. A later row is a header too if at least
+/// half of its fields match the header's, ignoring case and surrounding whitespace,
+/// so relabelled repeats like `ID, Name` still count. A row whose fields are all
+/// punctuation or whitespace, with at least one that isn't empty, is a separator.
+/// Rows are read as in [`stream_valid_csv_with_lines`], and each is positioned at
+/// the line it starts on. Reading stops at the first row that can't be parsed.
+pub fn classify_non_data_rows<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+) -> Vec<(Position, RowKind)> {
+    let mut rows = stream_valid_csv_with_lines(reader, dialect).map_while(|row| row.ok());
+    let Some((header_lines, header)) = rows.next() else {
+        return Vec::new();
+    };
+    let mut kinds = vec![(
+        Position {
+            line: header_lines.start,
+            column: 0,
+        },
+        RowKind::Header,
+    )];
+    for (lines, row) in rows {
+        let is_separator = row.iter().any(|field| !field.trim().is_empty())
+            && row.iter().all(|field| {
+                field
+                    .bytes()
+                    .all(|byte| byte.is_ascii_punctuation() || byte.is_ascii_whitespace())
+            });
+        let matching = row
+            .iter()
+            .zip(header.iter())
+            .filter(|(field, label)| field.trim().eq_ignore_ascii_case(label.trim()))
+            .count();
+        let kind = if is_separator {
+            RowKind::Separator
+        } else if matching > 0 && matching * 2 >= header.len().max(row.len()) {
+            RowKind::Header
+        } else {
+            RowKind::Data
+        };
+        kinds.push((
+            Position {
+                line: lines.start,
+                column: 0,
+            },
+            kind,
+        ));
+    }
+    kinds
+}
+
+/// The most common character class in a field, if it has any characters
+fn dominant_class(field: &str) -> Option<CharacterClass> {
+    let mut class_counts = BTreeMap::new();
+    for byte in field.bytes() {
+        *class_counts
+            .entry(CharacterClass::from_byte(byte))
+            .or_insert(0) += 1;
+    }
+    class_counts
+        .into_iter()
+        .max_by_key(|&(class, count)| (count, Reverse(class)))
+        .map(|(class, _)| class)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::CsvError;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_best_delimiter() {
+        let raw = b"a;b;c\n1;2,5;3\n4;5;6,5\n";
+        assert_eq!(best_delimiter(raw, b",;\t"), Some(b';'));
+        let raw = b"a\tb\n\"1\t2\"\t3\n";
+        assert_eq!(best_delimiter(raw, b",;\t"), Some(b'\t'));
+        assert_eq!(best_delimiter(b"abc\ndef\n", b",;\t"), None);
+    }
+
+    #[test]
+    fn test_detect_header() {
+        let dialect = Dialect::default();
+        let input = Cursor::new("id,price\n1,2.50\n2,3.75\n3,10.00\n");
+        assert!(detect_header(input, &dialect));
+        let input = Cursor::new("4,5.25\n1,2.50\n2,3.75\n3,10.00\n");
+        assert!(!detect_header(input, &dialect));
+    }
+
+    #[test]
+    fn test_column_count_changes() {
+        let dialect = Dialect::default();
+        let input = Cursor::new("a,b,c\n1,2,3\n4,5\n7,8,9\nw,x,y,z\n1,2,3,4\n5,6,7,8\n");
+        assert_eq!(
+            column_count_changes(input, &dialect, 3),
+            vec![Position { line: 4, column: 0 }]
+        );
+        let input = Cursor::new("a,b,c\n1,2,3\n4,5,6\n");
+        assert!(column_count_changes(input, &dialect, 2).is_empty());
+
+        // A comment and a quoted line break come before the change
+        let dialect = Dialect {
+            delimiter: ';',
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let input = Cursor::new("# first\na,b\n1,\"x\ny\"\nw;x;y\n1;2;3\n4;5;6\n");
+        assert_eq!(
+            column_count_changes(input, &dialect, 2),
+            vec![Position { line: 4, column: 0 }]
+        );
+    }
+
+    #[test]
+    fn test_suggest_invalid_column() {
+        let dialect = Dialect::default();
+        let input = Cursor::new(concat!(
+            "1,Great product. Would buy again!,45\n",
+            "2,\"Broke after a week, sadly.\",10\n",
+            "3,Fine, I guess,30\n",
+            "4,OK,35\n",
+        ));
+        assert_eq!(suggest_invalid_column(input, &dialect, 3), Some(1));
+        assert_eq!(
+            suggest_invalid_column(Cursor::new("1,2\n"), &dialect, 3),
+            None
+        );
+    }
+
+    #[test]
+    fn test_detect_quote_policy() {
+        let dialect = Dialect::default();
+        let always = Cursor::new("\"id\",\"name\"\n\"1\",\"Smith, J\"\n\"2\",\"\"\n");
+        assert_eq!(detect_quote_policy(always, &dialect), QuotePolicy::Always);
+        let minimal = Cursor::new("id,name\n1,\"Smith, J\"\n2,Lee\n");
+        assert_eq!(detect_quote_policy(minimal, &dialect), QuotePolicy::Minimal);
+    }
+
+    #[test]
+    fn test_estimate_row_count() {
+        let dialect = Dialect::default();
+        // Every record is 10 bytes, one of them with a quoted newline
+        let sample = b"1,abcdefg\n2,\"ab\ncd\"\n3,abcdefg\n4,ab";
+        assert_eq!(estimate_row_count(sample, 10_000, &dialect), Ok(1_000));
+        assert_eq!(estimate_row_count(b"1,abcdefg", 90, &dialect), Ok(10));
+        assert_eq!(estimate_row_count(b"", 90, &dialect), Ok(0));
+        // U+2022 would be read as the byte 0x22, a double quote
+        let dialect = Dialect {
+            quote: '\u{2022}',
+            ..Dialect::default()
+        };
+        assert_eq!(
+            estimate_row_count(sample, 10_000, &dialect),
+            Err(CsvError::invalid_at(0, 0, "Dialect character is not ASCII"))
+        );
+    }
+
+    #[test]
+    fn test_detect_bom() {
+        let utf16le = b"\xFF\xFEa\x00,\x00b\x00";
+        let encoding = detect_bom(utf16le).unwrap();
+        assert_eq!(encoding, Encoding::Utf16Le);
+        assert_eq!(&utf16le[encoding.bom_len()..], b"a\x00,\x00b\x00");
+        assert_eq!(detect_bom(b"\xEF\xBB\xBFa,b"), Some(Encoding::Utf8));
+        assert_eq!(detect_bom(b"\xFE\xFF\x00a"), Some(Encoding::Utf16Be));
+        assert_eq!(detect_bom(b"a,b"), None);
+    }
+
+    #[test]
+    fn test_classify_non_data_rows() {
+        let input = "id,name,city\n---,---,---\n1,Ada,Oslo\nID, Name ,town\n2,Bob,Rome\n,,\n";
+        let kinds = classify_non_data_rows(Cursor::new(input), &Dialect::default())
+            .into_iter()
+            .map(|(_, kind)| kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                RowKind::Header,
+                RowKind::Separator,
+                RowKind::Data,
+                RowKind::Header,
+                RowKind::Data,
+                RowKind::Data,
+            ]
+        );
+        let positions = classify_non_data_rows(Cursor::new(input), &Dialect::default());
+        assert_eq!(positions[3].0, Position { line: 3, column: 0 });
+
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let input = "# export\nid,name\n1,\"a\nb\"\nid,name\n";
+        assert_eq!(
+            classify_non_data_rows(Cursor::new(input), &dialect),
+            vec![
+                (Position { line: 1, column: 0 }, RowKind::Header),
+                (Position { line: 2, column: 0 }, RowKind::Data),
+                (Position { line: 4, column: 0 }, RowKind::Header),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_probe_delimiter_and_quote() {
+        let raw = b"name;price;note\n\"Ada; B\";3,50;\"x\"\nBob;4,25;\"y; z\"\n";
+        assert_eq!(probe_delimiter_and_quote(raw), Some((b';', Some(b'"'))));
+        let raw = b"a,b\n1,2\n3,4\n";
+        assert_eq!(probe_delimiter_and_quote(raw), Some((b',', None)));
+        let raw = b"a\t'b\tc'\n1\t'2'\n";
+        assert_eq!(probe_delimiter_and_quote(raw), Some((b'\t', Some(b'\''))));
+        assert_eq!(probe_delimiter_and_quote(b"abc\ndef\n"), None);
+    }
+}
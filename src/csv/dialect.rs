@@ -0,0 +1,71 @@
+//! The formatting conventions of a CSV file
+
+use std::time::Instant;
+
+/// How the fields and records of a CSV file are written
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dialect {
+    /// The character separating fields within a record
+    pub delimiter: char,
+    /// The character used to quote fields containing special characters
+    pub quote: char,
+    /// When to give up parsing, checked between records
+    pub deadline: Option<Instant>,
+    /// Whether to drop lines with nothing on them instead of reading one empty field
+    pub skip_empty_lines: bool,
+    /// The character that starts a comment line, if the format has them
+    pub comment: Option<char>,
+    /// How a quote is written inside a quoted field
+    pub escape: EscapeStyle,
+    /// What a carriage return that isn't part of `\r\n` means
+    pub cr_handling: CrHandling,
+    /// Whether to drop a UTF-8 byte order mark from the start of the input
+    pub strip_bom: bool,
+    /// Whether to ignore whitespace between a quoted field and the delimiters
+    /// around it, as in `"a" , "b"`
+    pub trim_after_quote: bool,
+}
+impl Default for Dialect {
+    /// RFC 4180 CSV: comma separated, double quoted
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            quote: '"',
+            deadline: None,
+            skip_empty_lines: false,
+            comment: None,
+            escape: EscapeStyle::DoubledQuote,
+            cr_handling: CrHandling::Newline,
+            strip_bom: true,
+            trim_after_quote: false,
+        }
+    }
+}
+
+/// How special characters are written inside a quoted field
+///
+/// The two styles are exclusive. With backslashes, `""` is a closing quote
+/// followed by an opening one, not a literal quote, and with doubled quotes a
+/// backslash is an ordinary character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscapeStyle {
+    /// A quote is written twice, as in RFC 4180: `"say ""hi"""`
+    DoubledQuote,
+    /// A backslash escapes the character after it: `\"` is a quote, `\\` a
+    /// backslash, and `\n` a line break. Other escapes are kept as they are.
+    Backslash,
+}
+
+/// What a lone carriage return means, one that isn't followed by `\n`
+///
+/// `\r\n` always ends a line outside quotes, whatever this says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CrHandling {
+    /// A line break, as in old Mac files, so outside quotes it ends the record
+    #[default]
+    Newline,
+    /// Stray whitespace, replaced by a space wherever it appears
+    Space,
+    /// An ordinary character, kept as it is
+    Keep,
+}
@@ -1,65 +1,1761 @@
-use crate::errors::{CsvError, Result};
+use crate::csv::detect::{best_delimiter, column_count_changes, Encoding};
+use crate::csv::dialect::{CrHandling, Dialect, EscapeStyle};
+use crate::csv::lookahead::LookaheadReader;
+use crate::errors::{CsvError, Result, Warning};
 use crate::Position;
-use std::io::BufRead;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::Instant;
 
-/// Read valid CSV one line at a time.
+/// How many lines are sampled when checking whether a delimiter works
+const FALLBACK_SAMPLE_LINES: usize = 10;
+
+/// Read valid CSV one record at a time.
+///
+/// Lines may end with `\r\n`, `\n`, or a lone `\r`, so files from any platform
+/// parse without a stray `\r` on the last field. Line breaks inside quoted fields
+/// are part of the field, which keeps a quoted `\r` intact and lets one record span
+/// several lines. If the input ends inside a quoted field, the last record is
+/// `CsvError::Invalid` at the quote that opened it. A UTF-8 byte order mark at the
+/// start of the input is dropped, so it doesn't end up in the first header.
 pub fn fast_stream_valid_csv<R: BufRead>(
     reader: R,
     delimiter: char,
     quote: char,
-) -> impl Iterator<Item = Result<Vec<String>>> {
-    reader.lines().into_iter().map(move |line_result| {
-        let line = line_result?;
-        let mut chars = line.chars().peekable();
-        let mut row: Vec<String> = Vec::new();
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    fast_stream_valid_csv_with_escape(reader, delimiter, quote, EscapeStyle::DoubledQuote)
+}
+
+/// Read valid CSV one record at a time, with quotes escaped in the given style.
+///
+/// This is [`fast_stream_valid_csv`] for files that don't double their quotes. With
+/// [`EscapeStyle::Backslash`], `\"` inside a quoted field is a literal quote
+/// that doesn't close it, `\\` is a backslash, and `\n` is a line break.
+/// Backslashes outside quotes are ordinary characters.
+pub fn fast_stream_valid_csv_with_escape<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+    escape: EscapeStyle,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let dialect = Dialect {
+        delimiter,
+        quote,
+        escape,
+        ..Dialect::default()
+    };
+    stream_records(reader, &dialect, Splitter::new(&dialect), TrimMode::None)
+}
+
+/// Read valid CSV one record at a time, with the options of a dialect
+///
+/// Records are framed as in [`read_records`] and split by `splitter`. The first
+/// record is the header as far as `trim` is concerned.
+fn stream_records<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    splitter: Splitter,
+    trim: TrimMode,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    stream_lined_records(reader, dialect, splitter, trim).map(|record| record.map(|(_, row)| row))
+}
+
+/// Read valid CSV one record at a time, each with the lines it spans
+fn stream_lined_records<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    splitter: Splitter,
+    trim: TrimMode,
+) -> impl Iterator<Item = Result<(Range<usize>, Vec<String>)>> {
+    split_records(read_records(reader, dialect), splitter, trim)
+}
+
+/// Split raw records, each with the lines it spans, into fields
+fn split_records(
+    records: impl Iterator<Item = Result<(Range<usize>, Vec<u8>)>>,
+    splitter: Splitter,
+    trim: TrimMode,
+) -> impl Iterator<Item = Result<(Range<usize>, Vec<String>)>> {
+    let mut header = true;
+    records.map(move |record| {
+        let (lines, record) = record?;
+        let trim = trim.applies(header);
+        header = false;
+        let row = splitter
+            .split(decode_text(&record, lines.start)?, trim)
+            .map_err(|index| {
+                let (line, column) = position_in_record(&record, lines.start, index);
+                CsvError::invalid_at(
+                    line,
+                    column,
+                    "Row has more fields than the maximum column count",
+                )
+            })?;
+        check_closed_quotes(&record, splitter.quote, splitter.backslash(), lines.start)?;
+        Ok((lines, row))
+    })
+}
+
+/// Read the raw records of a dialect, each with the lines it spans
+///
+/// The lines are counted from the start of the input, end exclusive. A line only
+/// counts as empty if nothing at all is left once its terminator is removed, so
+/// a line holding just a delimiter is still a record of empty fields. Comments
+/// are only recognised at the start of a record, never inside quotes or after
+/// the first field, and skipped lines still count towards positions. If the
+/// dialect has a deadline, it is checked before each record, and once it has
+/// passed this yields one `CsvError::Invalid` at the next line and then stops.
+fn read_records<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+) -> impl Iterator<Item = Result<(Range<usize>, Vec<u8>)>> {
+    read_bounded_records(reader, dialect, usize::MAX)
+}
+
+/// Read the raw records of a dialect, refusing those longer than `max_record_bytes`
+///
+/// This is [`read_records`], except that no more than `max_record_bytes` of a
+/// record are ever held in memory. A longer record is `CsvError::Invalid` at its
+/// first byte past the limit, and the rest of it is skipped, so reading carries
+/// on with the next record.
+fn read_bounded_records<R: BufRead>(
+    mut reader: R,
+    dialect: &Dialect,
+    max_record_bytes: usize,
+) -> impl Iterator<Item = Result<(Range<usize>, Vec<u8>)>> {
+    let Dialect {
+        quote,
+        deadline,
+        skip_empty_lines,
+        comment,
+        escape,
+        cr_handling,
+        mut strip_bom,
+        ..
+    } = *dialect;
+    let comment = comment.and_then(ascii_byte);
+    let backslash = (escape == EscapeStyle::Backslash).then_some(b'\\');
+    let mut record = Vec::new();
+    let mut next_line = 0;
+    let mut expired = false;
+    std::iter::from_fn(move || loop {
+        if expired {
+            return None;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            expired = true;
+            return Some(Err(CsvError::invalid_at(next_line, 0, "Deadline exceeded")));
+        }
+        record.clear();
+        if std::mem::take(&mut strip_bom) {
+            match skip_bom(&mut reader) {
+                Ok(()) => {}
+                Err(error) => return Some(Err(error.into())),
+            }
+        }
+        if let Some(comment) = comment {
+            match skip_comment_line(&mut reader, comment, &mut record) {
+                Ok(true) => {
+                    next_line += 1;
+                    continue;
+                }
+                Ok(false) => {}
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        let read = read_bounded_record(
+            &mut reader,
+            ascii_byte(quote),
+            backslash,
+            cr_handling,
+            max_record_bytes,
+            &mut record,
+        );
+        if cr_handling == CrHandling::Space {
+            record
+                .iter_mut()
+                .filter(|byte| **byte == b'\r')
+                .for_each(|byte| *byte = b' ');
+        }
+        let line = next_line;
+        next_line += 1 + record.iter().filter(|&&byte| byte == b'\n').count();
+        match read {
+            Ok(RecordRead::End) => return None,
+            Ok(RecordRead::TooLong(skipped_lines)) => {
+                next_line += skipped_lines;
+                let (line, column) = position_in_record(&record, line, record.len());
+                return Some(Err(CsvError::invalid_at(
+                    line,
+                    column,
+                    "Line is longer than the maximum line length",
+                )));
+            }
+            Ok(RecordRead::Complete) if skip_empty_lines && record.is_empty() => {}
+            Ok(RecordRead::Complete) => {
+                return Some(Ok((line..next_line, std::mem::take(&mut record))))
+            }
+            Err(error) => return Some(Err(error.into())),
+        }
+    })
+}
+
+/// Check that a record read by [`read_terminated_record`] didn't end inside quotes
+///
+/// That only happens at the end of the input, and the error points at the quote
+/// that opened the field that was never closed.
+fn check_closed_quotes(record: &[u8], quote: char, escape: Option<u8>, line: usize) -> Result<()> {
+    let Some(quote) = ascii_byte(quote) else {
+        return Ok(());
+    };
+    let mut opening = None;
+    let mut bytes = record.iter().enumerate().peekable();
+    while let Some((index, &byte)) = bytes.next() {
+        if opening.is_some() && Some(byte) == escape {
+            bytes.next();
+            continue;
+        }
+        if byte != quote {
+            continue;
+        }
+        if opening.is_none() {
+            opening = Some(index);
+        } else if escape.is_some() || bytes.next_if(|&(_, &next)| next == quote).is_none() {
+            // A doubled quote inside a quoted field is a literal quote
+            opening = None;
+        }
+    }
+    let Some(opening) = opening else {
+        return Ok(());
+    };
+    let (line, column) = position_in_record(record, line, opening);
+    Err(CsvError::invalid_at(
+        line,
+        column,
+        "Unterminated quoted field",
+    ))
+}
+
+/// The line and character column of a byte in a record that starts on `line`
+///
+/// Records can span several lines, so the column counts from the last line break
+/// before the byte, not from the start of the record.
+fn position_in_record(record: &[u8], line: usize, index: usize) -> (usize, usize) {
+    let line_start = record[..index]
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    let lines = record[..line_start]
+        .iter()
+        .filter(|&&byte| byte == b'\n')
+        .count();
+    let column = String::from_utf8_lossy(&record[line_start..index])
+        .chars()
+        .count();
+    (line + lines, column)
+}
+
+/// Skip a UTF-8 byte order mark at the front of `reader`, if there is one
+///
+/// Only a mark that is all in the reader's buffer is seen, which it always is at
+/// the start of the input unless the buffer is tiny.
+fn skip_bom<R: BufRead>(reader: &mut R) -> std::io::Result<()> {
+    let bom = Encoding::Utf8.bom();
+    if reader.fill_buf()?.starts_with(bom) {
+        reader.consume(bom.len());
+    }
+    Ok(())
+}
+
+/// The byte for a character, if it is ASCII
+///
+/// The byte-level readers only track quotes and comments that are a single byte.
+fn ascii_byte(ch: char) -> Option<u8> {
+    u8::try_from(ch).ok().filter(u8::is_ascii)
+}
+
+/// The byte for a character of a dialect, for readers that scan bytes
+///
+/// A non-ASCII character would be truncated to an unrelated byte, so it is
+/// `CsvError::Invalid` instead.
+pub fn dialect_byte(ch: char) -> Result<u8> {
+    ascii_byte(ch).ok_or(CsvError::invalid_at(0, 0, "Dialect character is not ASCII"))
+}
+
+/// Skip the line at the front of `reader` if it is a comment
+///
+/// A comment line has `comment` as its first character other than spaces and tabs.
+/// Those leading spaces and tabs are consumed either way, and if the line isn't a
+/// comment they are appended to `prefix` to start the record.
+fn skip_comment_line<R: BufRead>(
+    reader: &mut R,
+    comment: u8,
+    prefix: &mut Vec<u8>,
+) -> Result<bool> {
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(false);
+        }
+        let blanks = available
+            .iter()
+            .take_while(|&&byte| byte == b' ' || byte == b'\t')
+            .count();
+        let next = available.get(blanks).copied();
+        prefix.extend_from_slice(&available[..blanks]);
+        reader.consume(blanks);
+        match next {
+            // The buffer was all blanks, so look at the next one
+            None => continue,
+            Some(byte) if byte == comment => {
+                read_terminated_record(reader, None, None, CrHandling::Newline, prefix)?;
+                prefix.clear();
+                return Ok(true);
+            }
+            Some(_) => return Ok(false),
+        }
+    }
+}
+
+/// Read one record ending in `\r\n`, `\n`, `\r`, or the end of the input
+///
+/// The record is appended to `record`, without its terminator, and line breaks
+/// between quotes don't end it. Without a quote, this reads one line. Between
+/// quotes, `escape` hides the byte after it. A lone `\r` only ends the record if
+/// `cr_handling` says it is a line break. Returns false at the end of the input,
+/// unless `record` already had something in it.
+fn read_terminated_record<R: BufRead>(
+    reader: &mut R,
+    quote: Option<u8>,
+    escape: Option<u8>,
+    cr_handling: CrHandling,
+    record: &mut Vec<u8>,
+) -> std::io::Result<bool> {
+    read_bounded_record(reader, quote, escape, cr_handling, usize::MAX, record)
+        .map(|read| read != RecordRead::End)
+}
+
+/// How a call to [`read_bounded_record`] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordRead {
+    /// There was nothing left to read
+    End,
+    /// The whole record was read
+    Complete,
+    /// The record was too long, so only its start was kept, and the rest of it,
+    /// with this many line breaks in it, was skipped
+    TooLong(usize),
+}
+
+/// Read one record as in [`read_terminated_record`], keeping at most `max_bytes` of it
+///
+/// Once `record` would grow past `max_bytes`, it is filled up to exactly that and
+/// the rest of the record is read without being kept, still minding quotes, so
+/// the next call starts on the next record.
+fn read_bounded_record<R: BufRead>(
+    reader: &mut R,
+    quote: Option<u8>,
+    escape: Option<u8>,
+    cr_handling: CrHandling,
+    max_bytes: usize,
+    record: &mut Vec<u8>,
+) -> std::io::Result<RecordRead> {
+    let cr_ends_line = cr_handling == CrHandling::Newline;
+    let mut read_anything = !record.is_empty();
+    let mut within_quotes = false;
+    let mut escaped = false;
+    // How many line breaks have been skipped, once the record is too long
+    let mut skipped_lines = None;
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(match skipped_lines {
+                _ if !read_anything => RecordRead::End,
+                Some(skipped_lines) => RecordRead::TooLong(skipped_lines),
+                None => RecordRead::Complete,
+            });
+        }
+        read_anything = true;
+        let terminator = available.iter().position(|&byte| {
+            if escaped {
+                escaped = false;
+                return false;
+            }
+            if within_quotes && Some(byte) == escape {
+                escaped = true;
+            } else if Some(byte) == quote {
+                within_quotes = !within_quotes;
+            }
+            !within_quotes && (byte == b'\n' || (cr_ends_line && byte == b'\r'))
+        });
+        let chunk = &available[..terminator.unwrap_or(available.len())];
+        let room = max_bytes.saturating_sub(record.len());
+        match skipped_lines.as_mut() {
+            Some(skipped_lines) => *skipped_lines += count_newlines(chunk),
+            None if chunk.len() > room => {
+                record.extend_from_slice(&chunk[..room]);
+                skipped_lines = Some(count_newlines(&chunk[room..]));
+            }
+            None => record.extend_from_slice(chunk),
+        }
+        let Some(end) = terminator else {
+            let consumed = available.len();
+            reader.consume(consumed);
+            continue;
+        };
+        let carriage_return = available[end] == b'\r';
+        reader.consume(end + 1);
+        if skipped_lines.is_none() && !cr_ends_line && record.last() == Some(&b'\r') {
+            // The record ended in `\r\n`, which is a line break however lone `\r` are read
+            record.pop();
+        }
+        if carriage_return && reader.fill_buf()?.first() == Some(&b'\n') {
+            reader.consume(1);
+        }
+        return Ok(skipped_lines.map_or(RecordRead::Complete, RecordRead::TooLong));
+    }
+}
+
+/// How many line feeds there are in some bytes
+fn count_newlines(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&byte| byte == b'\n').count()
+}
+
+/// Somewhere raw records come from, one at a time, without their framing
+///
+/// The field parser doesn't care how records are delimited on the wire, so sources
+/// with their own framing, like length-prefixed messages, can implement this and
+/// be parsed with [`parse_records`].
+pub trait RecordSource {
+    /// The bytes of the next record, or `None` once there are no more
+    fn next_record_bytes(&mut self) -> Option<std::io::Result<Vec<u8>>>;
+}
+
+/// Records that end in line breaks, as in an ordinary CSV file
+///
+/// Records end in `\r\n`, `\n`, or `\r`, except between quotes, the same way
+/// as in [`fast_stream_valid_csv`].
+pub struct LineRecords<R> {
+    reader: R,
+    quote: Option<u8>,
+}
+impl<R> LineRecords<R> {
+    /// Split a reader into records, with line breaks between `quote`s kept
+    pub fn new(reader: R, quote: char) -> Self {
+        Self {
+            reader,
+            quote: ascii_byte(quote),
+        }
+    }
+}
+impl<R: BufRead> RecordSource for LineRecords<R> {
+    fn next_record_bytes(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        let mut record = Vec::new();
+        match read_terminated_record(
+            &mut self.reader,
+            self.quote,
+            None,
+            CrHandling::Newline,
+            &mut record,
+        ) {
+            Ok(false) => None,
+            Ok(true) => Some(Ok(record)),
+            Err(error) => Some(Err(error)),
+        }
+    }
+}
+
+/// Parse valid CSV records from any source, one at a time.
+///
+/// Each record is split into fields as in [`fast_stream_valid_csv`], and one that
+/// ends inside quotes is `CsvError::Invalid` at the quote that opened it. Positions
+/// count lines from the start of the source, including line breaks inside records.
+pub fn parse_records<S: RecordSource>(
+    mut source: S,
+    delimiter: char,
+    quote: char,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let mut next_line = 0;
+    std::iter::from_fn(move || {
+        let record = match source.next_record_bytes()? {
+            Ok(record) => record,
+            Err(error) => return Some(Err(error.into())),
+        };
+        let line = next_line;
+        next_line += 1 + record.iter().filter(|&&byte| byte == b'\n').count();
+        Some(
+            decode_text(&record, line)
+                .map(|text| split_record(text, delimiter, quote))
+                .and_then(|row| check_closed_quotes(&record, quote, None, line).map(|_| row)),
+        )
+    })
+}
+
+/// Read valid CSV one record at a time, with records ending in `terminator`.
+///
+/// This is [`fast_stream_valid_csv`] for feeds that don't use line breaks, such as
+/// ones ending records with `\u{1e}`. The terminator can be several bytes long, is
+/// not recognised between quotes, and is not part of any field. An empty
+/// terminator makes the whole input one record. A record that ends inside quotes
+/// is `CsvError::Invalid` at the quote that opened it. Positions count line feeds
+/// from the start of the input, wherever they are, and columns on the first line
+/// of a record count from the start of the record.
+pub fn stream_valid_csv_with_terminator<R: BufRead>(
+    mut reader: R,
+    delimiter: char,
+    quote: char,
+    terminator: &[u8],
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let terminator = terminator.to_vec();
+    let terminator_lines = count_newlines(&terminator);
+    let mut record = Vec::new();
+    let mut next_line = 0;
+    std::iter::from_fn(move || {
+        let read = read_record_until(&mut reader, quote, &terminator, &mut record);
+        let line = next_line;
+        next_line += count_newlines(&record) + terminator_lines;
+        match read {
+            Ok(false) => None,
+            Ok(true) => Some(
+                decode_record(&record, delimiter, quote, line)
+                    .and_then(|row| check_closed_quotes(&record, quote, None, line).map(|_| row)),
+            ),
+            Err(error) => Some(Err(error)),
+        }
+    })
+}
+
+/// Read valid CSV one record at a time, with fields separated by a string.
+///
+/// This is [`fast_stream_valid_csv`] for formats that separate fields with several
+/// characters, such as `||`. The delimiter is matched left to right outside quotes,
+/// and each match is consumed whole, so `a|||b` split on `||` is `a` and `|b`. Any
+/// shorter run, like a single `|`, is ordinary text.
+pub fn stream_valid_csv_str_delim<R: BufRead>(
+    mut reader: R,
+    delimiter: &str,
+    quote: char,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let delimiter = delimiter.to_string();
+    let mut record = Vec::new();
+    let mut next_line = 0;
+    std::iter::from_fn(move || {
+        record.clear();
+        let read = read_terminated_record(
+            &mut reader,
+            ascii_byte(quote),
+            None,
+            CrHandling::Newline,
+            &mut record,
+        );
+        let line = next_line;
+        next_line += 1 + record.iter().filter(|&&byte| byte == b'\n').count();
+        match read {
+            Ok(false) => None,
+            Ok(true) => Some(
+                decode_text(&record, line)
+                    .map(|text| split_record_str_delim(text, &delimiter, quote))
+                    .and_then(|row| check_closed_quotes(&record, quote, None, line).map(|_| row)),
+            ),
+            Err(error) => Some(Err(error.into())),
+        }
+    })
+}
+
+/// Split one line of valid CSV into fields separated by a string
+fn split_record_str_delim(line: &str, delimiter: &str, quote: char) -> Vec<String> {
+    let mut row = Vec::new();
+    let mut current_field = String::new();
+    let mut within_quotes = false;
+    let mut rest = line;
+    while let Some(ch) = rest.chars().next() {
+        if !within_quotes && !delimiter.is_empty() && rest.starts_with(delimiter) {
+            row.push(std::mem::take(&mut current_field));
+            rest = &rest[delimiter.len()..];
+            continue;
+        }
+        rest = &rest[ch.len_utf8()..];
+        if ch == quote {
+            if within_quotes && rest.starts_with(quote) {
+                // Two quotes in a row inside a quoted field means a literal quote
+                current_field.push(quote);
+                rest = &rest[quote.len_utf8()..];
+            } else {
+                within_quotes = !within_quotes;
+            }
+        } else {
+            current_field.push(ch);
+        }
+    }
+    row.push(current_field);
+    row
+}
+
+/// Read one record ending in `terminator` or the end of the input
+///
+/// Like [`read_terminated_record`], but for any terminator, even one split across
+/// two reads from the underlying buffer.
+fn read_record_until<R: BufRead>(
+    reader: &mut R,
+    quote: char,
+    terminator: &[u8],
+    record: &mut Vec<u8>,
+) -> Result<bool> {
+    let quote = ascii_byte(quote);
+    record.clear();
+    let mut read_anything = false;
+    let mut within_quotes = false;
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(read_anything);
+        }
+        read_anything = true;
+        let mut consumed = 0;
+        let mut terminated = false;
+        for &byte in available {
+            consumed += 1;
+            record.push(byte);
+            if Some(byte) == quote {
+                within_quotes = !within_quotes;
+            } else if !within_quotes && !terminator.is_empty() && record.ends_with(terminator) {
+                record.truncate(record.len() - terminator.len());
+                terminated = true;
+                break;
+            }
+        }
+        reader.consume(consumed);
+        if terminated {
+            return Ok(true);
+        }
+    }
+}
+
+/// Read valid CSV one line at a time, in the given dialect.
+///
+/// If the dialect has a deadline, it is checked before each record. Once it has
+/// passed, the iterator yields one `CsvError::Invalid` at the line the next record
+/// would start on and then stops, so a slow reader can't hold the caller up much
+/// past the deadline. Empty lines and comment lines are dropped if the dialect
+/// skips them.
+pub fn stream_valid_csv<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    stream_records(reader, dialect, Splitter::new(dialect), TrimMode::None)
+}
+
+/// Read valid CSV one record at a time, with the lines each record spans
+///
+/// This is [`stream_valid_csv`] for callers that report positions of their own.
+/// The lines are counted from the start of the input, including comment lines,
+/// skipped empty lines and line breaks inside quoted fields, and the range ends
+/// at the line after the record.
+pub fn stream_valid_csv_with_lines<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+) -> impl Iterator<Item = Result<(Range<usize>, Vec<String>)>> {
+    stream_lined_records(reader, dialect, Splitter::new(dialect), TrimMode::None)
+}
+
+/// Which rows have the whitespace around their fields removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimMode {
+    /// Keep all whitespace
+    None,
+    /// Trim every row but the header
+    Fields,
+    /// Trim only the header
+    Headers,
+    /// Trim every row
+    All,
+}
+impl TrimMode {
+    /// Whether a row is trimmed, given whether it is the header
+    fn applies(self, header: bool) -> bool {
+        match self {
+            TrimMode::None => false,
+            TrimMode::Fields => !header,
+            TrimMode::Headers => header,
+            TrimMode::All => true,
+        }
+    }
+}
+
+/// Read valid CSV one record at a time, trimming whitespace around fields.
+///
+/// This is [`stream_valid_csv`] with ASCII whitespace removed from both ends of
+/// each field in the rows `trim` selects, taking the first row as the header. Only
+/// whitespace outside quotes is removed, so `a, "  b  " ,c` keeps the spaces in
+/// `"  b  "`.
+pub fn stream_valid_csv_trimmed<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    trim: TrimMode,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    stream_records(reader, dialect, Splitter::new(dialect), trim)
+}
+
+/// Read the rows of valid CSV whose field in one column passes a test.
+///
+/// Records are framed as in [`stream_valid_csv`]. Only the fields up to that column
+/// are split before the predicate is called, and the rest of the record is split
+/// only if it matches, so rejected rows cost little more than a scan. Rows too
+/// short to have the column are skipped.
+pub fn filter_by_column<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    column: usize,
+    predicate: impl Fn(&str) -> bool,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let splitter = Splitter::new(dialect);
+    let leading = splitter.with_kept_columns(column.saturating_add(1));
+    read_records(reader, dialect)
+        .map(move |record| {
+            let (lines, record) = record?;
+            let text = decode_text(&record, lines.start)?;
+            check_closed_quotes(&record, splitter.quote, splitter.backslash(), lines.start)?;
+            let leading = leading.split(text, false).unwrap_or_default();
+            match leading.get(column) {
+                Some(key) if predicate(key) => {
+                    Ok(Some(splitter.split(text, false).unwrap_or_default()))
+                }
+                _ => Ok(None),
+            }
+        })
+        .filter_map(Result::transpose)
+}
+
+/// Read valid CSV one line at a time, falling back to other delimiters if needed.
+///
+/// The first few lines are split with the primary delimiter. If every one of them
+/// comes out as a single column, the file almost certainly uses something else,
+/// so each fallback is tried in turn and the first that splits any sampled line
+/// into several columns is used for the whole file. If none do, the primary is used.
+pub fn primary_then_fallback<R: BufRead>(
+    mut reader: R,
+    primary: char,
+    fallbacks: &[char],
+    quote: char,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let mut sample = Vec::new();
+    let mut sample_error = None;
+    for _ in 0..FALLBACK_SAMPLE_LINES {
+        match reader.read_until(b'\n', &mut sample) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(error) => {
+                sample_error = Some(CsvError::from(error));
+                break;
+            }
+        }
+    }
+    let sample_text = String::from_utf8_lossy(&sample);
+    let delimiter = std::iter::once(primary)
+        .chain(fallbacks.iter().copied())
+        .find(|&delimiter| {
+            sample_text
+                .lines()
+                .any(|line| split_record(line, delimiter, quote).len() > 1)
+        })
+        .unwrap_or(primary);
+    sample_error
+        .map(Err)
+        .into_iter()
+        .chain(fast_stream_valid_csv(
+            Cursor::new(sample).chain(reader),
+            delimiter,
+            quote,
+        ))
+}
+
+/// Read valid CSV one record at a time, refusing records longer than `max_line_bytes`.
+///
+/// Records are framed as in [`fast_stream_valid_csv`], so a quoted line break
+/// stays inside its record. Unlike there, a record is never held in memory past
+/// the limit, so a huge input without newlines can't exhaust memory. An overlong
+/// record is reported as `CsvError::Invalid` at its first byte past the limit,
+/// and the rest of it is skipped without buffering, after which reading
+/// continues from the next record.
+pub fn fast_stream_valid_csv_with_line_limit<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+    max_line_bytes: usize,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let dialect = Dialect {
+        delimiter,
+        quote,
+        ..Dialect::default()
+    };
+    let records = read_bounded_records(reader, &dialect, max_line_bytes);
+    split_records(records, Splitter::new(&dialect), TrimMode::None)
+        .map(|record| record.map(|(_, row)| row))
+}
+
+/// Read valid CSV one record at a time, refusing rows with more than `max_columns` fields.
+///
+/// Records are framed as in [`fast_stream_valid_csv`]. An oversized row is reported as `CsvError::Invalid` at the delimiter that would
+/// start the first extra field, before any fields past the limit are allocated.
+pub fn fast_stream_valid_csv_with_column_limit<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+    max_columns: usize,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let dialect = Dialect {
+        delimiter,
+        quote,
+        ..Dialect::default()
+    };
+    let splitter = Splitter::new(&dialect).with_max_columns(max_columns);
+    stream_records(reader, &dialect, splitter, TrimMode::None)
+}
+
+/// Read CSV one line at a time, rejoining quoted fields split across lines.
+///
+/// When a line ends inside a quoted field, up to `lookahead` following lines are
+/// checked for the closing quote. If it is found, the lines are joined with `\n`
+/// into one record. Otherwise the line is reported as `CsvError::Invalid` at the
+/// line where it started, and reading resumes with the line after it.
+pub fn fast_stream_csv_rejoining_lines<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+    lookahead: usize,
+) -> RejoinedRows<R> {
+    RejoinedRows {
+        lines: LookaheadReader::new(reader, lookahead),
+        delimiter,
+        quote,
+        lookahead,
+        close_quote_at_eof: false,
+        warnings: Vec::new(),
+    }
+}
+
+/// Rows read by [`fast_stream_csv_rejoining_lines`]
+pub struct RejoinedRows<R> {
+    lines: LookaheadReader<R>,
+    delimiter: char,
+    quote: char,
+    lookahead: usize,
+    close_quote_at_eof: bool,
+    warnings: Vec<Warning>,
+}
+impl<R> RejoinedRows<R> {
+    /// Close a quoted field left open at the end of the input instead of failing
+    ///
+    /// This salvages files that were truncated mid-field, such as interrupted
+    /// downloads. The last field is kept as it is, and a warning is recorded.
+    pub fn close_quote_at_eof(mut self, enabled: bool) -> Self {
+        self.close_quote_at_eof = enabled;
+        self
+    }
+
+    /// The warnings for the rows read so far
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+}
+impl<R: BufRead> Iterator for RejoinedRows<R> {
+    type Item = Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.lines.line();
+        let record = match self.lines.next()? {
+            Ok(record) => record,
+            Err(error) => return Some(Err(error)),
+        };
+        if !has_open_quote(&record, self.quote) {
+            return Some(Ok(split_record(&record, self.delimiter, self.quote)));
+        }
+        let mut joined = record.clone();
+        let mut end_of_input = self.lookahead == 0 && self.lines.peek(0).is_none();
+        for offset in 0..self.lookahead {
+            match self.lines.peek(offset) {
+                Some(Ok(next_line)) => {
+                    joined.push('\n');
+                    joined.push_str(next_line);
+                }
+                Some(Err(_)) => break,
+                None => {
+                    end_of_input = true;
+                    break;
+                }
+            }
+            if !has_open_quote(&joined, self.quote) {
+                for _ in 0..=offset {
+                    self.lines.next();
+                }
+                return Some(Ok(split_record(&joined, self.delimiter, self.quote)));
+            }
+        }
+        let position = Position {
+            line,
+            column: record.chars().count(),
+        };
+        if end_of_input && self.close_quote_at_eof {
+            // Everything left was joined onto this record
+            for _ in self.lines.by_ref() {}
+            self.warnings.push(Warning {
+                position,
+                message: "Quoted field closed at the end of the input",
+            });
+            return Some(Ok(split_record(&joined, self.delimiter, self.quote)));
+        }
+        Some(Err(CsvError::Invalid(
+            position,
+            "Quoted field is not closed within the lookahead",
+        )))
+    }
+}
+
+/// Parses valid CSV as it arrives, such as from a file that is still being written
+///
+/// Bytes are added with [`IncrementalRecords::push`], and a record only comes out
+/// of [`IncrementalRecords::next_record`] once its terminator has arrived, so a
+/// half-written last line is held back rather than returned truncated. Records are
+/// split as in [`fast_stream_valid_csv`]. Call [`IncrementalRecords::finish`] once
+/// no more bytes will come, to get a last record with no terminator.
+#[derive(Debug, Clone)]
+pub struct IncrementalRecords {
+    delimiter: char,
+    quote: char,
+    buffer: Vec<u8>,
+    /// How much of the buffer has been searched for a terminator
+    scanned: usize,
+    within_quotes: bool,
+    next_line: usize,
+}
+impl IncrementalRecords {
+    /// Start parsing with no bytes yet
+    pub fn new(delimiter: char, quote: char) -> Self {
+        Self {
+            delimiter,
+            quote,
+            buffer: Vec::new(),
+            scanned: 0,
+            within_quotes: false,
+            next_line: 0,
+        }
+    }
+
+    /// Add bytes to the end of the input
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete record, or `None` until more bytes arrive
+    ///
+    /// A `\r` at the very end of what has arrived so far is held back too, in
+    /// case it is the start of `\r\n`.
+    pub fn next_record(&mut self) -> Option<Result<Vec<String>>> {
+        let quote = ascii_byte(self.quote);
+        while self.scanned < self.buffer.len() {
+            let index = self.scanned;
+            let byte = self.buffer[index];
+            if Some(byte) == quote {
+                self.within_quotes = !self.within_quotes;
+            } else if !self.within_quotes && (byte == b'\n' || byte == b'\r') {
+                let terminator_len = match (byte, self.buffer.get(index + 1)) {
+                    (b'\r', None) => return None,
+                    (b'\r', Some(b'\n')) => 2,
+                    _ => 1,
+                };
+                let mut record = self
+                    .buffer
+                    .drain(..index + terminator_len)
+                    .collect::<Vec<_>>();
+                record.truncate(index);
+                self.scanned = 0;
+                return Some(self.split(&record));
+            }
+            self.scanned += 1;
+        }
+        None
+    }
+
+    /// The last record, if the input ended without a terminator after it
+    ///
+    /// If the input ended inside a quoted field, this is `CsvError::Invalid` at the
+    /// quote that opened it.
+    pub fn finish(mut self) -> Option<Result<Vec<String>>> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        if self.buffer.last() == Some(&b'\r') && !self.within_quotes {
+            self.buffer.pop();
+        }
+        let record = std::mem::take(&mut self.buffer);
+        Some(self.split(&record))
+    }
+
+    /// Split a record whose terminator has been removed
+    fn split(&mut self, record: &[u8]) -> Result<Vec<String>> {
+        let line = self.next_line;
+        self.next_line += 1 + record.iter().filter(|&&byte| byte == b'\n').count();
+        self.within_quotes = false;
+        let row = split_record(decode_text(record, line)?, self.delimiter, self.quote);
+        check_closed_quotes(record, self.quote, None, line)?;
+        Ok(row)
+    }
+}
+
+/// Read the header and a page of rows from valid CSV.
+///
+/// The reader is rewound to the start, the header is read, and then `start` records
+/// are skipped by scanning for the newlines that end them, without splitting or
+/// decoding their fields. After that, up to `count` rows are parsed. Records may
+/// contain quoted newlines, and rows are numbered from zero after the header.
+pub fn parse_range<R: BufRead + Seek>(
+    mut reader: R,
+    dialect: &Dialect,
+    start: usize,
+    count: usize,
+) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut quote = [0; 4];
+    let quote = dialect.quote.encode_utf8(&mut quote).as_bytes();
+    let mut record = Vec::new();
+    let mut line = 0;
+    if !read_record(&mut reader, quote, &mut record)? {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let header = decode_record(&record, dialect.delimiter, dialect.quote, line)?;
+    for _ in 0..start {
+        line += 1;
+        if !read_record(&mut reader, quote, &mut record)? {
+            return Ok((header, Vec::new()));
+        }
+    }
+    let mut rows = Vec::with_capacity(count);
+    while rows.len() < count {
+        line += 1;
+        if !read_record(&mut reader, quote, &mut record)? {
+            break;
+        }
+        rows.push(decode_record(
+            &record,
+            dialect.delimiter,
+            dialect.quote,
+            line,
+        )?);
+    }
+    Ok((header, rows))
+}
+
+/// Read one record, which may span lines if a quoted field contains newlines
+///
+/// The record replaces the contents of `record`, without its terminator. Returns
+/// false at the end of the input.
+fn read_record<R: BufRead>(reader: &mut R, quote: &[u8], record: &mut Vec<u8>) -> Result<bool> {
+    record.clear();
+    let mut quotes = 0;
+    loop {
+        let line_start = record.len();
+        if reader.read_until(b'\n', record)? == 0 {
+            return Ok(!record.is_empty());
+        }
+        quotes += record[line_start..]
+            .windows(quote.len())
+            .filter(|&window| window == quote)
+            .count();
+        if quotes % 2 == 0 && record.last() == Some(&b'\n') {
+            record.pop();
+            if record.last() == Some(&b'\r') {
+                record.pop();
+            }
+            return Ok(true);
+        }
+    }
+}
+
+/// Decode and split one record, without its terminator
+fn decode_record(record: &[u8], delimiter: char, quote: char, line: usize) -> Result<Vec<String>> {
+    Ok(split_record(decode_text(record, line)?, delimiter, quote))
+}
+
+/// Decode one record as UTF-8
+///
+/// The error points at the first character that isn't valid.
+fn decode_text(record: &[u8], line: usize) -> Result<&str> {
+    std::str::from_utf8(record).map_err(|error| {
+        let (line, column) = position_in_record(record, line, error.valid_up_to());
+        CsvError::invalid_at(line, column, "Line is not valid UTF-8")
+    })
+}
+
+/// Read valid CSV one record at a time, decoding only some columns lossily.
+///
+/// Fields in `lossy_columns` have invalid UTF-8 replaced with `U+FFFD`, while
+/// invalid UTF-8 anywhere else is reported as `CsvError::Invalid` at the start of
+/// the field. This keeps strict checking on the columns that should be text while
+/// tolerating a column known to hold binary-ish data. Records are framed as in
+/// [`fast_stream_valid_csv`], and a record that ends inside quotes is
+/// `CsvError::Invalid` the same way. The delimiter and quote are matched as bytes,
+/// so if either isn't ASCII this yields a single `CsvError::Invalid` instead.
+pub fn fast_stream_csv_with_lossy_columns<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+    lossy_columns: &[usize],
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let lossy_columns = lossy_columns.to_vec();
+    let dialect = Dialect {
+        delimiter,
+        quote,
+        ..Dialect::default()
+    };
+    let (error, bytes) =
+        match dialect_byte(delimiter).and_then(|delimiter| Ok((delimiter, dialect_byte(quote)?))) {
+            Ok(bytes) => (None, Some(bytes)),
+            Err(error) => (Some(error), None),
+        };
+    let rows = bytes.map(move |(delimiter_byte, quote_byte)| {
+        read_records(reader, &dialect).map(move |record| {
+            let (lines, record) = record?;
+            check_closed_quotes(&record, quote, None, lines.start)?;
+            split_record_bytes(&record, delimiter_byte, quote_byte)
+                .into_iter()
+                .enumerate()
+                .map(|(column, (start, field))| {
+                    if lossy_columns.contains(&column) {
+                        return Ok(String::from_utf8_lossy(&field).into_owned());
+                    }
+                    String::from_utf8(field).map_err(|_| {
+                        let (line, column) = position_in_record(&record, lines.start, start);
+                        CsvError::invalid_at(line, column, "Field is not valid UTF-8")
+                    })
+                })
+                .collect()
+        })
+    });
+    error.map(Err).into_iter().chain(rows.into_iter().flatten())
+}
+
+/// Split one line of valid CSV into fields, as bytes, with where each one starts
+fn split_record_bytes(record: &[u8], delimiter: u8, quote: u8) -> Vec<(usize, Vec<u8>)> {
+    let mut bytes = record.iter().enumerate().peekable();
+    let mut row = Vec::new();
+    let mut field_start = 0;
+    let mut current_field = Vec::new();
+    let mut within_quotes = false;
+
+    while let Some((index, &byte)) = bytes.next() {
+        if byte == quote {
+            if within_quotes && bytes.peek().map(|&(_, &next)| next) == Some(quote) {
+                // Two quotes in a row inside a quoted field means a literal quote
+                current_field.push(quote);
+                bytes.next();
+            } else {
+                within_quotes = !within_quotes;
+            }
+        } else if byte == delimiter && !within_quotes {
+            row.push((field_start, std::mem::take(&mut current_field)));
+            field_start = index + 1;
+        } else {
+            current_field.push(byte);
+        }
+    }
+    row.push((field_start, current_field));
+    row
+}
+
+/// Parse valid CSV held in memory, borrowing fields from the input.
+///
+/// Unquoted fields, and quoted fields without doubled quotes, are slices of
+/// `input`; only a quoted field with an escaped quote is copied to unescape it.
+/// Records end in `\r\n`, `\n`, or `\r`, as in [`fast_stream_valid_csv`]. A quote
+/// only opens a quoted field at the start of the field, and a quoted field that
+/// isn't closed, or is followed by anything but a delimiter or line break, is an
+/// error that ends the iteration. Its position counts the line breaks inside
+/// earlier quoted fields too.
+pub fn parse_valid_csv_bytes(
+    input: &[u8],
+    delimiter: u8,
+    quote: u8,
+) -> impl Iterator<Item = Result<Vec<Cow<'_, [u8]>>>> {
+    let mut offset = 0;
+    let mut line = 0;
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed || offset >= input.len() {
+            return None;
+        }
+        let record_start = offset;
+        let mut row = Vec::new();
+        let record_end = loop {
+            let field_end;
+            if input.get(offset) == Some(&quote) {
+                match quoted_field_bytes(&input[offset + 1..], quote) {
+                    Some((field, length)) => {
+                        row.push(field);
+                        field_end = offset + 1 + length;
+                    }
+                    None => {
+                        failed = true;
+                        let (line, column) =
+                            position_in_record(&input[record_start..], line, offset - record_start);
+                        return Some(Err(CsvError::invalid_at(
+                            line,
+                            column,
+                            "Unterminated quoted field",
+                        )));
+                    }
+                }
+            } else {
+                let length = input[offset..]
+                    .iter()
+                    .position(|&byte| byte == delimiter || byte == b'\n' || byte == b'\r')
+                    .unwrap_or(input.len() - offset);
+                row.push(Cow::Borrowed(&input[offset..offset + length]));
+                field_end = offset + length;
+            }
+            match input.get(field_end) {
+                Some(&byte) if byte == delimiter => offset = field_end + 1,
+                Some(b'\r') if input.get(field_end + 1) == Some(&b'\n') => {
+                    offset = field_end + 2;
+                    break field_end;
+                }
+                Some(b'\n' | b'\r') | None => {
+                    offset = field_end + 1;
+                    break field_end;
+                }
+                Some(_) => {
+                    failed = true;
+                    let (line, column) =
+                        position_in_record(&input[record_start..], line, field_end - record_start);
+                    return Some(Err(CsvError::invalid_at(
+                        line,
+                        column,
+                        "Unexpected character after a closing quote",
+                    )));
+                }
+            }
+        };
+        line += 1 + count_newlines(&input[record_start..record_end]);
+        Some(Ok(row))
+    })
+}
+
+/// Read a quoted field, starting just after its opening quote
+///
+/// Returns the unescaped contents and how many bytes were read, including the
+/// closing quote, or `None` if the quote is never closed.
+fn quoted_field_bytes(input: &[u8], quote: u8) -> Option<(Cow<'_, [u8]>, usize)> {
+    let mut escaped = false;
+    let mut index = 0;
+    loop {
+        let close = index + input[index..].iter().position(|&byte| byte == quote)?;
+        if input.get(close + 1) == Some(&quote) {
+            escaped = true;
+            index = close + 2;
+            continue;
+        }
+        let contents = &input[..close];
+        let field = if escaped {
+            let mut unescaped = Vec::with_capacity(contents.len());
+            let mut bytes = contents.iter().peekable();
+            while let Some(&byte) = bytes.next() {
+                unescaped.push(byte);
+                if byte == quote && bytes.peek() == Some(&&quote) {
+                    bytes.next();
+                }
+            }
+            Cow::Owned(unescaped)
+        } else {
+            Cow::Borrowed(contents)
+        };
+        return Some((field, close + 1));
+    }
+}
+
+/// A run of rows sharing one delimiter, from a file made of several
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    /// Where the section's first row starts
+    pub position: Position,
+    /// The delimiter detected for the section
+    pub delimiter: char,
+    /// The rows of the section
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Parse a file whose delimiter changes from one section to the next.
+///
+/// The delimiter is picked from `candidates` using the first `window` lines, and
+/// the file is parsed with it until [`column_count_changes`] finds a break in
+/// structure. The delimiter is then detected again from the break onwards, and so
+/// on. A section where no candidate splits the lines is read as one column.
+pub fn parse_sections(raw: &[u8], candidates: &[u8], window: usize) -> Result<Vec<Section>> {
+    let window = window.max(1);
+    let mut sections = Vec::new();
+    let mut offset = 0;
+    let mut line = 0;
+    while offset < raw.len() {
+        let rest = &raw[offset..];
+        let sample_end = rest
+            .iter()
+            .enumerate()
+            .filter(|&(_, &byte)| byte == b'\n')
+            .nth(window - 1)
+            .map_or(rest.len(), |(newline, _)| newline + 1);
+        let delimiter = best_delimiter(&rest[..sample_end], candidates)
+            .or(candidates.first().copied())
+            .map_or(',', char::from);
+        let dialect = Dialect {
+            delimiter,
+            ..Dialect::default()
+        };
+        let section_lines = column_count_changes(Cursor::new(rest), &dialect, window)
+            .first()
+            .map_or(usize::MAX, |change| change.line.max(1));
+
+        // Read the section's records, keeping track of how far into the input they go
+        let mut reader = Cursor::new(rest);
+        let mut record = Vec::new();
+        let mut rows = Vec::new();
+        let mut lines = 0;
+        while lines < section_lines {
+            record.clear();
+            if !read_terminated_record(
+                &mut reader,
+                ascii_byte(dialect.quote),
+                None,
+                CrHandling::Newline,
+                &mut record,
+            )? {
+                break;
+            }
+            let record_line = line + lines;
+            lines += 1 + record.iter().filter(|&&byte| byte == b'\n').count();
+            rows.push(decode_record(
+                &record,
+                delimiter,
+                dialect.quote,
+                record_line,
+            )?);
+        }
+        sections.push(Section {
+            position: Position { line, column: 0 },
+            delimiter,
+            rows,
+        });
+        offset += reader.position() as usize;
+        line += lines;
+    }
+    Ok(sections)
+}
+
+/// Whether a line ends inside a quoted field
+///
+/// Doubled quotes come in pairs, so only an odd number of quotes leaves one open.
+fn has_open_quote(line: &str, quote: char) -> bool {
+    line.matches(quote).count() % 2 == 1
+}
+
+/// Split one line of valid CSV into fields
+///
+/// Fields may be quoted, and a doubled quote inside a quoted field is a literal
+/// quote. This is the field splitting of the streaming parsers on its own, for
+/// when the lines come from somewhere else.
+pub fn split_record(line: &str, delimiter: char, quote: char) -> Vec<String> {
+    let dialect = Dialect {
+        delimiter,
+        quote,
+        ..Dialect::default()
+    };
+    Splitter::new(&dialect)
+        .split(line, false)
+        .unwrap_or_default()
+}
+
+/// How records are split into fields
+///
+/// The dialect sets most of this. The rest is for readers that bend the usual
+/// rules, so they can still share the record framing of [`read_records`].
+#[derive(Debug, Clone, Copy)]
+struct Splitter {
+    delimiter: char,
+    quote: char,
+    escape: EscapeStyle,
+    trim_after_quote: bool,
+    delimiter_escape: DelimiterEscape,
+    max_columns: usize,
+    kept_columns: usize,
+}
+impl Splitter {
+    /// Split records as a dialect says to
+    fn new(dialect: &Dialect) -> Self {
+        Self {
+            delimiter: dialect.delimiter,
+            quote: dialect.quote,
+            escape: dialect.escape,
+            trim_after_quote: dialect.trim_after_quote,
+            delimiter_escape: DelimiterEscape::Quoting,
+            max_columns: usize::MAX,
+            kept_columns: usize::MAX,
+        }
+    }
+
+    /// Read a doubled delimiter outside quotes as one literal delimiter, or not
+    fn with_delimiter_escape(self, delimiter_escape: DelimiterEscape) -> Self {
+        Self {
+            delimiter_escape,
+            ..self
+        }
+    }
+
+    /// Refuse records with more than `max_columns` fields
+    fn with_max_columns(self, max_columns: usize) -> Self {
+        Self {
+            max_columns,
+            ..self
+        }
+    }
+
+    /// Stop splitting after the first `kept_columns` fields, dropping the rest
+    fn with_kept_columns(self, kept_columns: usize) -> Self {
+        Self {
+            kept_columns,
+            ..self
+        }
+    }
+
+    /// The byte that escapes the next one inside quotes, if there is one
+    fn backslash(&self) -> Option<u8> {
+        (self.escape == EscapeStyle::Backslash).then_some(b'\\')
+    }
+
+    /// Split one record of valid CSV into fields
+    ///
+    /// If `trim` is set, ASCII whitespace outside quotes is removed from both ends of
+    /// each field. If `trim_after_quote` is set, whitespace is only removed between a
+    /// quoted part of a field and the delimiters around it. If the record has more
+    /// than `max_columns` fields, this stops at the delimiter that would start the
+    /// first extra one and returns its byte offset instead. Fields past the first
+    /// `kept_columns` aren't split at all.
+    fn split(&self, line: &str, trim: bool) -> std::result::Result<Vec<String>, usize> {
+        let Splitter {
+            delimiter,
+            quote,
+            escape,
+            trim_after_quote,
+            delimiter_escape,
+            max_columns,
+            kept_columns,
+        } = *self;
+        let mut chars = line.char_indices().peekable();
+        let mut row = Vec::new();
         let mut current_field = String::new();
         let mut within_quotes = false;
+        let mut started = false;
+        let mut after_quote = false;
+        // How much of the field ends inside quotes, and so can't be trimmed
+        let mut protected = 0;
+        // Whitespace that is dropped if it turns out to be next to a quote
+        let mut pending = String::new();
 
-        while let Some(ch) = chars.next() {
-            if ch == quote {
-                if within_quotes && chars.peek() == Some(&quote) {
+        let finish = |mut field: String, protected: usize| {
+            if trim {
+                let trimmed = field
+                    .trim_end_matches(|ch: char| ch.is_ascii_whitespace())
+                    .len();
+                field.truncate(trimmed.max(protected));
+            }
+            field
+        };
+        while let Some((index, ch)) = chars.next() {
+            if within_quotes && escape == EscapeStyle::Backslash && ch == '\\' {
+                match chars.next().map(|(_, next)| next) {
+                    Some('n') => current_field.push('\n'),
+                    Some(next) if next == quote || next == '\\' => current_field.push(next),
+                    Some(next) => {
+                        current_field.push('\\');
+                        current_field.push(next);
+                    }
+                    None => current_field.push('\\'),
+                }
+                protected = current_field.len();
+            } else if ch == quote {
+                if within_quotes
+                    && escape == EscapeStyle::DoubledQuote
+                    && chars.next_if(|&(_, next)| next == quote).is_some()
+                {
                     // Two quotes in a row inside a quoted field means a literal quote
                     current_field.push(quote);
-                    chars.next();
                 } else {
+                    if !within_quotes && started {
+                        current_field.push_str(&pending);
+                    }
+                    pending.clear();
+                    after_quote = within_quotes;
                     within_quotes = !within_quotes;
                 }
+                started = true;
+                protected = current_field.len();
+            } else if ch == delimiter
+                && !within_quotes
+                && delimiter_escape == DelimiterEscape::Doubling
+                && chars.next_if(|&(_, next)| next == delimiter).is_some()
+            {
+                // Two delimiters in a row are one literal delimiter
+                current_field.push_str(&pending);
+                pending.clear();
+                current_field.push(delimiter);
+                started = true;
+                after_quote = false;
             } else if ch == delimiter && !within_quotes {
-                row.push(current_field);
-                current_field = String::new();
+                if row.len() + 1 >= max_columns {
+                    return Err(index);
+                }
+                if !after_quote {
+                    current_field.push_str(&pending);
+                }
+                pending.clear();
+                row.push(finish(std::mem::take(&mut current_field), protected));
+                if row.len() >= kept_columns {
+                    return Ok(row);
+                }
+                started = false;
+                after_quote = false;
+                protected = 0;
+            } else if !within_quotes && ch.is_ascii_whitespace() && trim && !started {
+                // Leading whitespace
+            } else if !within_quotes
+                && ch.is_ascii_whitespace()
+                && trim_after_quote
+                && (after_quote || !started)
+            {
+                pending.push(ch);
             } else {
-                current_field.push(ch as char);
+                current_field.push_str(&pending);
+                pending.clear();
+                current_field.push(ch);
+                started = true;
+                after_quote = false;
+                if within_quotes {
+                    protected = current_field.len();
+                }
             }
         }
-        row.push(current_field);
-
+        if !after_quote {
+            current_field.push_str(&pending);
+        }
+        row.push(finish(current_field, protected));
         Ok(row)
+    }
+}
+
+/// A row that could not be parsed, with what the parser saw of it
+#[derive(Debug, PartialEq)]
+pub struct FailedRow {
+    /// Where the row starts
+    pub position: Position,
+    /// The bytes of the record, without its terminator
+    pub raw: Vec<u8>,
+    /// How many fields the record appeared to have
+    pub field_count: usize,
+    /// Why the row was rejected
+    pub error: CsvError,
+}
+
+/// Parse a whole file, setting aside the rows that fail instead of stopping.
+///
+/// Records are framed as in [`stream_valid_csv`], so a quoted field can span
+/// several lines, and comments and empty lines are skipped if the dialect says
+/// so. A row fails if it isn't valid UTF-8, if it ends inside a quoted field, which
+/// only happens at the end of the input, or if its field count differs from the
+/// first row's. Every failed row is returned with its raw bytes so it can be
+/// inspected or repaired later.
+pub fn parse_with_trace<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+) -> (Vec<Vec<String>>, Vec<FailedRow>) {
+    let mut rows = Vec::new();
+    let mut failures = Vec::new();
+    let mut expected_field_count = None;
+    let mut next_line = 0;
+    for record in read_records(reader, dialect) {
+        let (lines, raw) = match record {
+            Ok(record) => record,
+            Err(error) => {
+                failures.push(FailedRow {
+                    position: Position {
+                        line: next_line,
+                        column: 0,
+                    },
+                    raw: Vec::new(),
+                    field_count: 0,
+                    error,
+                });
+                continue;
+            }
+        };
+        next_line = lines.end;
+        let (row, error) = check_record(&raw, lines.start, dialect, expected_field_count);
+        let field_count = row.len();
+        match error {
+            Some(error) => failures.push(FailedRow {
+                position: Position {
+                    line: lines.start,
+                    column: 0,
+                },
+                raw,
+                field_count,
+                error,
+            }),
+            None => {
+                expected_field_count.get_or_insert(field_count);
+                rows.push(row);
+            }
+        }
+    }
+    (rows, failures)
+}
+
+/// Split one raw record, and say why it should be rejected if it should be
+///
+/// A record is rejected if it isn't valid UTF-8, if it ends inside a quoted field,
+/// or if its field count differs from `expected_field_count`. It is split anyway,
+/// with invalid UTF-8 replaced, so the fields can be counted.
+fn check_record(
+    raw: &[u8],
+    line: usize,
+    dialect: &Dialect,
+    expected_field_count: Option<usize>,
+) -> (Vec<String>, Option<CsvError>) {
+    let text = String::from_utf8_lossy(raw);
+    let splitter = Splitter::new(dialect);
+    let row = splitter.split(&text, false).unwrap_or_default();
+    let error = decode_text(raw, line)
+        .and_then(|_| check_closed_quotes(raw, dialect.quote, splitter.backslash(), line))
+        .err()
+        .or_else(|| match expected_field_count {
+            Some(expected) if expected != row.len() => Some(CsvError::invalid_at(
+                line,
+                expected,
+                "Row has a different number of fields than the first row",
+            )),
+            _ => None,
+        });
+    (row, error)
+}
+
+/// Read CSV one record at a time, diverting malformed records to a sidecar.
+///
+/// Records are framed and rejected the same way as in [`parse_with_trace`]. Each
+/// rejected one is written to `error_writer` as a two-field CSV record of the raw
+/// record, decoded lossily, and the error message, and the good rows stream
+/// through the returned iterator. Only a failure to read or to write the sidecar
+/// is yielded as an error.
+pub fn split_valid_invalid<R: BufRead, W: Write>(
+    reader: R,
+    dialect: &Dialect,
+    mut error_writer: W,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let dialect = dialect.clone();
+    let mut expected_field_count = None;
+    read_records(reader, &dialect).filter_map(move |record| {
+        let (lines, raw) = match record {
+            Ok(record) => record,
+            Err(error) => return Some(Err(error)),
+        };
+        let (row, error) = check_record(&raw, lines.start, &dialect, expected_field_count);
+        let Some(error) = error else {
+            expected_field_count.get_or_insert(row.len());
+            return Some(Ok(row));
+        };
+        let record = [
+            escape_csv_field(&String::from_utf8_lossy(&raw), &dialect),
+            escape_csv_field(&error.to_string(), &dialect),
+        ]
+        .join(&dialect.delimiter.to_string());
+        writeln!(error_writer, "{record}")
+            .err()
+            .map(|error| Err(error.into()))
     })
 }
 
+/// Quote a field if it contains a delimiter, quote, or line break.
+fn escape_csv_field(field: &str, dialect: &Dialect) -> String {
+    if field.contains([dialect.delimiter, dialect.quote, '\n', '\r']) {
+        let doubled = format!("{0}{0}", dialect.quote);
+        let escaped = field.replace(dialect.quote, &doubled);
+        format!("{0}{escaped}{0}", dialect.quote)
+    } else {
+        field.to_string()
+    }
+}
+
+/// How a literal delimiter is written inside a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelimiterEscape {
+    /// The field is quoted, as in RFC 4180
+    Quoting,
+    /// The delimiter is doubled, so `a,,b` is the single field `a,b`
+    ///
+    /// This conflicts with empty fields: `a,,b` can't also mean `["a", "", "b"]`,
+    /// so files in this dialect can't contain empty fields between delimiters.
+    Doubling,
+}
+
+/// Read valid CSV one record at a time, with a choice of delimiter escape.
+///
+/// With [`DelimiterEscape::Quoting`] this is the same as [`fast_stream_valid_csv`],
+/// and records are framed the same way either way, so quoted fields may span lines.
+/// Quoted fields are still understood with [`DelimiterEscape::Doubling`], but a doubled
+/// delimiter outside of quotes is read as one literal delimiter instead of an empty field.
+pub fn fast_stream_csv_with_delimiter_escape<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+    delimiter_escape: DelimiterEscape,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let dialect = Dialect {
+        delimiter,
+        quote,
+        ..Dialect::default()
+    };
+    let splitter = Splitter::new(&dialect).with_delimiter_escape(delimiter_escape);
+    stream_records(reader, &dialect, splitter, TrimMode::None)
+}
+
 /// Read CSV and handle unescaped delimiters in one field.
 ///
 /// This requires that there are no unexpected newlines.
 /// If you have both unescaped delimiters and unexpected newlines, then parsing will be ambiguous
 /// so there are no deterministic solutions.
+///
+/// If `tolerate_trailing_delimiter` is set, a row with exactly one extra column that is empty
+/// is assumed to come from a trailing delimiter, and the empty column is dropped instead of
+/// being merged into the invalid column.
 pub fn fast_stream_csv_with_unescaped_delimiters<R: BufRead>(
     reader: R,
     delimiter: char,
     quote: char,
     invalid_column_index: usize,
     expected_column_count: usize,
+    tolerate_trailing_delimiter: bool,
 ) -> impl Iterator<Item = Result<Vec<String>>> {
     fast_stream_valid_csv(reader, delimiter, quote)
         .enumerate()
         .map(move |(line, row_result)| {
-            let row = row_result?;
+            let mut row = row_result?;
             let apparent_column_count = row.len();
+            if tolerate_trailing_delimiter
+                && apparent_column_count == expected_column_count + 1
+                && row.last().is_some_and(|field| field.is_empty())
+            {
+                row.pop();
+                return Ok(row);
+            }
             if apparent_column_count < expected_column_count {
-                return Err(CsvError::Invalid(
-                    Position {
-                        line,
-                        column: expected_column_count,
-                    },
+                return Err(CsvError::invalid_at(
+                    line,
+                    expected_column_count,
                     "Not enough columns. There may be an unescaped newline in a field.",
                 ));
             } else if apparent_column_count > expected_column_count {
@@ -85,72 +1781,1119 @@ pub fn fast_stream_csv_with_unescaped_delimiters<R: BufRead>(
         })
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
+/// Read valid CSV in paragraph mode, where blank lines separate groups of rows.
+///
+/// Each item is one group of consecutive rows. Runs of several blank lines, and blank
+/// lines at the start or end of the file, never produce empty groups.
+pub fn fast_stream_csv_paragraphs<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+) -> impl Iterator<Item = Result<Vec<Vec<String>>>> {
+    let mut rows = fast_stream_valid_csv(reader, delimiter, quote);
+    std::iter::from_fn(move || {
+        let mut group = Vec::new();
+        for row_result in rows.by_ref() {
+            let row = match row_result {
+                Ok(row) => row,
+                Err(error) => return Some(Err(error)),
+            };
+            let is_blank = row.len() == 1 && row[0].is_empty();
+            if !is_blank {
+                group.push(row);
+            } else if !group.is_empty() {
+                return Some(Ok(group));
+            }
+        }
+        (!group.is_empty()).then_some(Ok(group))
+    })
+}
+
+/// Split one column of every row into several, on a separator.
+///
+/// This undoes a coarse repair, such as the merge done by
+/// [`fast_stream_csv_with_unescaped_delimiters`], once the structure of the merged
+/// column is known. The separator is matched literally, and rows too short to have
+/// the column are passed through unchanged.
+pub fn resplit_column<I: Iterator<Item = Result<Vec<String>>>>(
+    rows: I,
+    column: usize,
+    separator: &str,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let separator = separator.to_string();
+    rows.map(move |row_result| {
+        let mut row = row_result?;
+        if column < row.len() {
+            let parts = row[column]
+                .split(separator.as_str())
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            row.splice(column..=column, parts);
+        }
+        Ok(row)
+    })
+}
+
+/// What to do when a name appears more than once in a header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateHeaderPolicy {
+    /// Fail with `CsvError::Invalid` at the repeated name
+    Error,
+    /// Rename repeats with a numeric suffix, so `id,id` becomes `id,id_1`
+    Suffix,
+    /// Leave the names alone, and look names up in the first column with them
+    KeepFirst,
+}
+
+/// Resolve repeated names in a header according to a policy.
+///
+/// A suffix that would clash with another name in the header is skipped, so
+/// `id,id,id_1` becomes `id,id_2,id_1`.
+pub fn dedupe_header(header: Vec<String>, policy: DuplicateHeaderPolicy) -> Result<Vec<String>> {
+    let mut seen: HashSet<String> = HashSet::with_capacity(header.len());
+    let mut resolved = Vec::with_capacity(header.len());
+    for (column, name) in header.iter().enumerate() {
+        if seen.insert(name.clone()) {
+            resolved.push(name.clone());
+            continue;
+        }
+        match policy {
+            DuplicateHeaderPolicy::Error => {
+                return Err(CsvError::invalid_at(
+                    0,
+                    column,
+                    "Duplicate column name in header",
+                ));
+            }
+            DuplicateHeaderPolicy::Suffix => {
+                let renamed = (1..)
+                    .map(|suffix| format!("{name}_{suffix}"))
+                    .find(|candidate| !seen.contains(candidate) && !header.contains(candidate))
+                    .expect("there are more suffixes than names");
+                seen.insert(renamed.clone());
+                resolved.push(renamed);
+            }
+            DuplicateHeaderPolicy::KeepFirst => resolved.push(name.clone()),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Map the names in the header row of valid CSV to their column indices.
+///
+/// Quoted names are unescaped the same way as any other field, so `"first,name"`
+/// is one name. Repeated names are resolved with [`dedupe_header`].
+pub fn header_map<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    duplicates: DuplicateHeaderPolicy,
+) -> Result<HashMap<String, usize>> {
+    let header = fast_stream_valid_csv(reader, dialect.delimiter, dialect.quote)
+        .next()
+        .transpose()?
+        .unwrap_or_default();
+    let mut map = HashMap::new();
+    for (column, name) in dedupe_header(header, duplicates)?.into_iter().enumerate() {
+        map.entry(name).or_insert(column);
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fast_stream_valid_csv() {
+        let input = Cursor::new("a,b,c\n1,2,3\n4,5,6");
+        let mut iter = fast_stream_valid_csv(input, ',', '"');
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "b".to_string(), "c".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["1".to_string(), "2".to_string(), "3".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["4".to_string(), "5".to_string(), "6".to_string()]
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_fast_stream_csv_with_unescaped_delimiters() {
+        let input = Cursor::new("a,b,c\n1,2,3\n4,5,6,7,8,9");
+        let mut iter = fast_stream_csv_with_unescaped_delimiters(input, ',', '"', 2, 3, false);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "b".to_string(), "c".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["1".to_string(), "2".to_string(), "3".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["4".to_string(), "5".to_string(), "6,7,8,9".to_string()]
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_fast_stream_csv_with_unescaped_delimiters_error() {
+        let input = Cursor::new("a,b,c\n1,2,3\n4,5\n10,11,12");
+        let mut iter = fast_stream_csv_with_unescaped_delimiters(input, ',', '"', 2, 3, false);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "b".to_string(), "c".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["1".to_string(), "2".to_string(), "3".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 2, column: 3 },
+                "Not enough columns. There may be an unescaped newline in a field."
+            )
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["10".to_string(), "11".to_string(), "12".to_string()]
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_fast_stream_csv_with_unescaped_delimiters_trailing_delimiter() {
+        let input = Cursor::new("a,b,c,\n1,2,3\n4,5,6,");
+        let mut iter = fast_stream_csv_with_unescaped_delimiters(input, ',', '"', 2, 3, true);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "b".to_string(), "c".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["1".to_string(), "2".to_string(), "3".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["4".to_string(), "5".to_string(), "6".to_string()]
+        );
+        assert!(iter.next().is_none());
+
+        // Without the allowance, the empty column is merged into the invalid column
+        let input = Cursor::new("a,b,c,");
+        let mut iter = fast_stream_csv_with_unescaped_delimiters(input, ',', '"', 2, 3, false);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "b".to_string(), "c,".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_fast_stream_csv_paragraphs() {
+        let input = Cursor::new("a,b\n1,2\n\n\nc,d\n3,4\n\n");
+        let mut iter = fast_stream_csv_paragraphs(input, ',', '"');
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec![
+                vec!["a".to_string(), "b".to_string()],
+                vec!["1".to_string(), "2".to_string()]
+            ]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec![
+                vec!["c".to_string(), "d".to_string()],
+                vec!["3".to_string(), "4".to_string()]
+            ]
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_fast_stream_csv_with_delimiter_escape() {
+        let input = Cursor::new("a,,b\nc,,,d\n\"e,,f\",g");
+        let mut iter =
+            fast_stream_csv_with_delimiter_escape(input, ',', '"', DelimiterEscape::Doubling);
+        assert_eq!(iter.next().unwrap().unwrap(), vec!["a,b".to_string()]);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["c,".to_string(), "d".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["e,,f".to_string(), "g".to_string()]
+        );
+        assert!(iter.next().is_none());
+
+        // A quoted field may span lines, and \r\n ends a record
+        let input = Cursor::new("\"one\ntwo\",,x\r\ny,z\r\n");
+        let rows: Vec<_> =
+            fast_stream_csv_with_delimiter_escape(input, ',', '"', DelimiterEscape::Doubling)
+                .collect::<Result<_>>()
+                .unwrap();
+        assert_eq!(rows, vec![vec!["one\ntwo,x"], vec!["y", "z"]]);
+
+        let input = Cursor::new("a,,b");
+        let mut iter =
+            fast_stream_csv_with_delimiter_escape(input, ',', '"', DelimiterEscape::Quoting);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "".to_string(), "b".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_fast_stream_valid_csv_with_line_limit() {
+        let input = Cursor::new("a,b\r\n0123456789,x\nc,d");
+        let mut iter = fast_stream_valid_csv_with_line_limit(input, ',', '"', 5);
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 1, column: 5 },
+                "Line is longer than the maximum line length"
+            )
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["c".to_string(), "d".to_string()]
+        );
+        assert!(iter.next().is_none());
+
+        // Quoted line breaks stay in the record, and a lone \r ends one
+        let input = Cursor::new(&b"1,\"a\nb\"\r2,c\n\"x\ny\nzzzz\"\n\xff\n"[..]);
+        let mut iter = fast_stream_valid_csv_with_line_limit(input, ',', '"', 7);
+        assert_eq!(iter.next().unwrap().unwrap(), vec!["1", "a\nb"]);
+        assert_eq!(iter.next().unwrap().unwrap(), vec!["2", "c"]);
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            CsvError::invalid_at(5, 2, "Line is longer than the maximum line length")
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            CsvError::invalid_at(6, 0, "Line is not valid UTF-8")
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_with_trace() {
+        let input = Cursor::new("a,b\n1,\"two\nlines\"\n5,6,7\n8,9\n3,\"4\n");
+        let (rows, failures) = parse_with_trace(input, &Dialect::default());
+        assert_eq!(
+            rows,
+            vec![
+                vec!["a".to_string(), "b".to_string()],
+                vec!["1".to_string(), "two\nlines".to_string()],
+                vec!["8".to_string(), "9".to_string()],
+            ]
+        );
+        assert_eq!(
+            failures,
+            vec![
+                FailedRow {
+                    position: Position { line: 3, column: 0 },
+                    raw: b"5,6,7".to_vec(),
+                    field_count: 3,
+                    error: CsvError::Invalid(
+                        Position { line: 3, column: 2 },
+                        "Row has a different number of fields than the first row"
+                    ),
+                },
+                FailedRow {
+                    position: Position { line: 5, column: 0 },
+                    raw: b"3,\"4\n".to_vec(),
+                    field_count: 2,
+                    error: CsvError::Invalid(
+                        Position { line: 5, column: 2 },
+                        "Unterminated quoted field"
+                    ),
+                },
+            ]
+        );
+    }
 
     #[test]
-    fn test_fast_stream_valid_csv() {
-        let input = Cursor::new("a,b,c\n1,2,3\n4,5,6");
-        let mut iter = fast_stream_valid_csv(input, ',', '"');
+    fn test_fast_stream_csv_rejoining_lines() {
+        let input = Cursor::new("a,b\n1,\"two\nlines\"\n2,\"x\n3,4\n5,6\n7,8\n");
+        let mut iter = fast_stream_csv_rejoining_lines(input, ',', '"', 2);
         assert_eq!(
             iter.next().unwrap().unwrap(),
-            vec!["a".to_string(), "b".to_string(), "c".to_string()]
+            vec!["a".to_string(), "b".to_string()]
         );
         assert_eq!(
             iter.next().unwrap().unwrap(),
-            vec!["1".to_string(), "2".to_string(), "3".to_string()]
+            vec!["1".to_string(), "two\nlines".to_string()]
+        );
+        // The quote on line 3 is never closed within two lines
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 3, column: 4 },
+                "Quoted field is not closed within the lookahead"
+            )
         );
         assert_eq!(
             iter.next().unwrap().unwrap(),
-            vec!["4".to_string(), "5".to_string(), "6".to_string()]
+            vec!["3".to_string(), "4".to_string()]
         );
-        assert!(iter.next().is_none());
+        assert_eq!(iter.count(), 2);
     }
 
     #[test]
-    fn test_fast_stream_csv_with_unescaped_delimiters() {
-        let input = Cursor::new("a,b,c\n1,2,3\n4,5,6,7,8,9");
-        let mut iter = fast_stream_csv_with_unescaped_delimiters(input, ',', '"', 2, 3);
+    fn test_fast_stream_valid_csv_with_column_limit() {
+        let input = Cursor::new(format!("a,b,c\n\"x,y\",{}\n1,2\n", ",".repeat(10_000)));
+        let mut iter = fast_stream_valid_csv_with_column_limit(input, ',', '"', 3);
         assert_eq!(
             iter.next().unwrap().unwrap(),
             vec!["a".to_string(), "b".to_string(), "c".to_string()]
         );
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 1, column: 7 },
+                "Row has more fields than the maximum column count"
+            )
+        );
         assert_eq!(
             iter.next().unwrap().unwrap(),
-            vec!["1".to_string(), "2".to_string(), "3".to_string()]
+            vec!["1".to_string(), "2".to_string()]
+        );
+        assert!(iter.next().is_none());
+
+        // Quoted line breaks neither split the record nor count as fields
+        let input = Cursor::new("a,\"b\nc,d\",e\n\"x\ny\",z,w,v\n");
+        let mut iter = fast_stream_valid_csv_with_column_limit(input, ',', '"', 3);
+        assert_eq!(iter.next().unwrap().unwrap(), vec!["a", "b\nc,d", "e"]);
+        assert_eq!(
+            iter.next().unwrap().unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 3, column: 6 },
+                "Row has more fields than the maximum column count"
+            )
         );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_fast_stream_csv_rejoining_lines_close_quote_at_eof() {
+        let input = Cursor::new("a,b\n1,\"abc\ndef");
+        let mut iter = fast_stream_csv_rejoining_lines(input, ',', '"', 2).close_quote_at_eof(true);
         assert_eq!(
             iter.next().unwrap().unwrap(),
-            vec!["4".to_string(), "5".to_string(), "6,7,8,9".to_string()]
+            vec!["a".to_string(), "b".to_string()]
+        );
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["1".to_string(), "abc\ndef".to_string()]
         );
         assert!(iter.next().is_none());
+        assert_eq!(
+            iter.warnings(),
+            &[Warning {
+                position: Position { line: 1, column: 6 },
+                message: "Quoted field closed at the end of the input",
+            }]
+        );
+
+        let input = Cursor::new("a,\"abc");
+        let mut iter = fast_stream_csv_rejoining_lines(input, ',', '"', 2);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.warnings().is_empty());
     }
 
     #[test]
-    fn test_fast_stream_csv_with_unescaped_delimiters_error() {
-        let input = Cursor::new("a,b,c\n1,2,3\n4,5\n10,11,12");
-        let mut iter = fast_stream_csv_with_unescaped_delimiters(input, ',', '"', 2, 3);
+    fn test_resplit_column() {
+        let input = Cursor::new("a,b,c\n4,5,6,7,8\n");
+        let merged = fast_stream_csv_with_unescaped_delimiters(input, ',', '"', 2, 3, false);
+        let mut iter = resplit_column(merged.skip(1), 2, ",");
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec![
+                "4".to_string(),
+                "5".to_string(),
+                "6".to_string(),
+                "7".to_string(),
+                "8".to_string()
+            ]
+        );
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_primary_then_fallback() {
+        let input = Cursor::new("a\tb\tc\n1\t2.5\t3\n");
+        let mut iter = primary_then_fallback(input, ',', &[';', '\t'], '"');
         assert_eq!(
             iter.next().unwrap().unwrap(),
             vec!["a".to_string(), "b".to_string(), "c".to_string()]
         );
         assert_eq!(
             iter.next().unwrap().unwrap(),
-            vec!["1".to_string(), "2".to_string(), "3".to_string()]
+            vec!["1".to_string(), "2.5".to_string(), "3".to_string()]
+        );
+        assert!(iter.next().is_none());
+
+        // The primary is kept when it works, even if a fallback would too
+        let input = Cursor::new("a,b\tc\n");
+        let mut iter = primary_then_fallback(input, ',', &['\t'], '"');
+        assert_eq!(
+            iter.next().unwrap().unwrap(),
+            vec!["a".to_string(), "b\tc".to_string()]
         );
+    }
+
+    #[test]
+    fn test_split_record_quoted() {
         assert_eq!(
-            iter.next().unwrap().unwrap_err(),
+            split_record("1,\"Smith, J\",x", ',', '"'),
+            vec!["1", "Smith, J", "x"]
+        );
+    }
+
+    #[test]
+    fn test_split_record_doubled_quotes() {
+        assert_eq!(
+            split_record("\"say \"\"hi\"\"\",2", ',', '"'),
+            vec!["say \"hi\"", "2"]
+        );
+    }
+
+    #[test]
+    fn test_split_record_trailing_delimiter() {
+        assert_eq!(split_record("a;b;", ';', '"'), vec!["a", "b", ""]);
+    }
+
+    #[test]
+    fn test_header_map_quoted() {
+        let input = Cursor::new("\"first,name\",\"age\"\nAda,36\n");
+        let header = header_map(input, &Dialect::default(), DuplicateHeaderPolicy::Error).unwrap();
+        assert_eq!(header.len(), 2);
+        assert_eq!(header["first,name"], 0);
+        assert_eq!(header["age"], 1);
+    }
+
+    #[test]
+    fn test_stream_valid_csv_deadline() {
+        let input = "a,b\n1,2\n3,4\n";
+        let rows = stream_valid_csv(Cursor::new(input), &Dialect::default())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows.len(), 3);
+
+        let dialect = Dialect {
+            deadline: Some(Instant::now()),
+            ..Dialect::default()
+        };
+        let rows = stream_valid_csv(Cursor::new(input), &dialect).collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![Err(CsvError::Invalid(
+                Position { line: 0, column: 0 },
+                "Deadline exceeded"
+            ))]
+        );
+
+        // The error is at the line after the last record read, skipped lines and all
+        let input = "# note\n\"a\nb\",c\n1,2\n";
+        let dialect = Dialect {
+            deadline: Some(Instant::now() + Duration::from_millis(200)),
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let mut rows = stream_valid_csv(Cursor::new(input), &dialect);
+        assert_eq!(
+            rows.next(),
+            Some(Ok(vec!["a\nb".to_string(), "c".to_string()]))
+        );
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(
+            rows.next(),
+            Some(Err(CsvError::invalid_at(3, 0, "Deadline exceeded")))
+        );
+        assert_eq!(rows.next(), None);
+    }
+
+    #[test]
+    fn test_stream_valid_csv_with_lines() {
+        let input = "# note\nid,note\n\n1,\"two\nlines\"\n2,x";
+        let dialect = Dialect {
+            comment: Some('#'),
+            skip_empty_lines: true,
+            ..Dialect::default()
+        };
+        let lines = stream_valid_csv_with_lines(Cursor::new(input), &dialect)
+            .map(|record| record.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![1..2, 3..5, 5..6]);
+    }
+
+    #[test]
+    fn test_filter_by_column() {
+        let input = Cursor::new("1,\"Lee, B\",x\n2,Ada,y\n3,\"Lee, B\",z\n4\n");
+        let rows = filter_by_column(input, &Dialect::default(), 1, |name| name == "Lee, B")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec!["1", "Lee, B", "x"], vec!["3", "Lee, B", "z"]]
+        );
+
+        // Quoted line breaks stay inside the record, and comments are skipped
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let input = Cursor::new("# 1,Lee\n1,\"Lee\nB\",x\n2,\"b\"\"c\",y\n");
+        let rows = filter_by_column(input, &dialect, 1, |name| name.starts_with("Lee"))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["1", "Lee\nB", "x"]]);
+        let input = Cursor::new("2,\"b\"\"c\",y\n");
+        let rows = filter_by_column(input, &dialect, 1, |name| name == "b\"c")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["2", "b\"c", "y"]]);
+    }
+
+    #[test]
+    fn test_dedupe_header() {
+        let header = vec!["id".to_string(), "name".to_string(), "id".to_string()];
+        assert_eq!(
+            dedupe_header(header.clone(), DuplicateHeaderPolicy::Suffix).unwrap(),
+            vec!["id", "name", "id_1"]
+        );
+        assert_eq!(
+            dedupe_header(header.clone(), DuplicateHeaderPolicy::Error).unwrap_err(),
             CsvError::Invalid(
-                Position { line: 2, column: 3 },
-                "Not enough columns. There may be an unescaped newline in a field."
+                Position { line: 0, column: 2 },
+                "Duplicate column name in header"
             )
         );
+        let header = header_map(
+            Cursor::new("id,name,id\n"),
+            &Dialect::default(),
+            DuplicateHeaderPolicy::KeepFirst,
+        )
+        .unwrap();
+        assert_eq!(header["id"], 0);
+    }
+
+    #[test]
+    fn test_split_valid_invalid() {
+        let input = Cursor::new("a,b\n1,\"two\nlines\"\n3,4,5\n6,7\n");
+        let mut sidecar = Vec::new();
+        let rows = split_valid_invalid(input, &Dialect::default(), &mut sidecar)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
         assert_eq!(
-            iter.next().unwrap().unwrap(),
-            vec!["10".to_string(), "11".to_string(), "12".to_string()]
+            rows,
+            vec![vec!["a", "b"], vec!["1", "two\nlines"], vec!["6", "7"]]
+        );
+        let sidecar = String::from_utf8(sidecar).unwrap();
+        let errors = fast_stream_valid_csv(Cursor::new(sidecar), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0][0], "3,4,5");
+        assert!(errors[0][1].contains("Row has a different number of fields than the first row"));
+    }
+
+    /// A small, seeded xorshift generator, so failures are reproducible
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_fast_stream_valid_csv_never_panics() {
+        // Weighted towards the characters the parser treats specially
+        let alphabet = [
+            ',', '"', '\n', '\r', ' ', 'a', '1', 'é', '€', '😀', '\u{feff}', '\0',
+        ];
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for case in 0..500 {
+            let length = if case % 50 == 0 {
+                100_000
+            } else {
+                (next_random(&mut state) % 64) as usize
+            };
+            // Leave out quotes half the time, so the output can be checked exactly
+            let alphabet = if case % 2 == 0 {
+                &alphabet[..]
+            } else {
+                &alphabet[2..]
+            };
+            let input = (0..length)
+                .map(|_| alphabet[(next_random(&mut state) % alphabet.len() as u64) as usize])
+                .collect::<String>();
+            let mut rows =
+                fast_stream_valid_csv(Cursor::new(input.as_str()), ',', '"').collect::<Vec<_>>();
+            // Only a quote left open at the end of the input is an error
+            if rows.last().is_some_and(|row| row.is_err()) {
+                assert!(input.matches('"').count() % 2 == 1, "case {case}");
+                rows.pop();
+            }
+            let rows = rows.into_iter().collect::<Result<Vec<_>>>().unwrap();
+            if !input.contains('"') {
+                // A byte order mark is only dropped from the very start
+                let normalized = input
+                    .strip_prefix('\u{feff}')
+                    .unwrap_or(&input)
+                    .replace("\r\n", "\n")
+                    .replace('\r', "\n");
+                let lines = normalized.lines().collect::<Vec<_>>();
+                let rejoined = rows.iter().map(|row| row.join(",")).collect::<Vec<_>>();
+                assert_eq!(rejoined, lines, "case {case}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let input = "id,note\n0,a\n1,\"two\nlines\"\n2,c\n3,\"d,e\"\n4,f\n";
+        let full = fast_stream_csv_rejoining_lines(Cursor::new(input), ',', '"', 2)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let (header, rows) = parse_range(Cursor::new(input), &Dialect::default(), 2, 2).unwrap();
+        assert_eq!(header, full[0]);
+        assert_eq!(rows, full[3..5]);
+        let (_, rows) = parse_range(Cursor::new(input), &Dialect::default(), 4, 10).unwrap();
+        assert_eq!(rows, vec![vec!["4", "f"]]);
+    }
+
+    #[test]
+    fn test_fast_stream_valid_csv_line_endings() {
+        let expected = vec![vec!["a", "b"], vec!["1", "2"], vec!["3", "4"]];
+        for input in ["a,b\r\n1,2\r\n3,4\r\n", "a,b\n1,2\n3,4", "a,b\r1,2\r3,4\r"] {
+            let rows = fast_stream_valid_csv(Cursor::new(input), ',', '"')
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(rows, expected, "{input:?}");
+        }
+        let rows = fast_stream_valid_csv(Cursor::new("a,\"b\r\"\r\n1,\"2\r\n3\"\r\n"), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["a", "b\r"], vec!["1", "2\r\n3"]]);
+    }
+
+    #[test]
+    fn test_stream_valid_csv_with_terminator() {
+        let input = "a,b\u{1e}1,\"x\u{1e}y\"\u{1e}2,z";
+        let rows =
+            stream_valid_csv_with_terminator(Cursor::new(input), ',', '"', "\u{1e}".as_bytes())
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec!["a", "b"], vec!["1", "x\u{1e}y"], vec!["2", "z"]]
+        );
+
+        // A multi-byte terminator split across reads from the buffer
+        let input = std::io::BufReader::with_capacity(3, Cursor::new("a,b\r\n1,\"2\r\n\"\r\n"));
+        let rows = stream_valid_csv_with_terminator(input, ',', '"', b"\r\n")
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["a", "b"], vec!["1", "2\r\n"]]);
+
+        // An unclosed quote is an error at the physical line where it opened
+        let input = Cursor::new("a,\"b\nc\"\u{1e}\nd,\"e\u{1e}f");
+        let mut rows = stream_valid_csv_with_terminator(input, ',', '"', "\u{1e}".as_bytes());
+        assert_eq!(rows.next().unwrap().unwrap(), vec!["a", "b\nc"]);
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::invalid_at(2, 2, "Unterminated quoted field")
+        );
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn test_fast_stream_csv_with_lossy_columns() {
+        let input: &[u8] = b"id,blob,name\n1,\"\xff\xfe\",Ada\n2,x,\xff\n";
+        let mut rows = fast_stream_csv_with_lossy_columns(Cursor::new(input), ',', '"', &[1]);
+        assert_eq!(rows.next().unwrap().unwrap(), vec!["id", "blob", "name"]);
+        assert_eq!(
+            rows.next().unwrap().unwrap(),
+            vec!["1", "\u{fffd}\u{fffd}", "Ada"]
+        );
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::Invalid(Position { line: 2, column: 4 }, "Field is not valid UTF-8")
+        );
+        assert!(rows.next().is_none());
+
+        // Positions are on physical lines, and an unclosed quote is an error
+        let input: &[u8] = b"\"a\nb\",x,\xff\na,\"x\n";
+        let mut rows = fast_stream_csv_with_lossy_columns(Cursor::new(input), ',', '"', &[1]);
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::Invalid(Position { line: 1, column: 5 }, "Field is not valid UTF-8")
+        );
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::Invalid(Position { line: 2, column: 2 }, "Unterminated quoted field")
+        );
+        assert!(rows.next().is_none());
+
+        let mut rows = fast_stream_csv_with_lossy_columns(Cursor::new(input), '\u{2022}', '"', &[]);
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::invalid_at(0, 0, "Dialect character is not ASCII")
+        );
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_valid_csv_bytes() {
+        let input = b"a,\"b,c\",\"say \"\"hi\"\"\"\r\n1,,\"x\ny\"\n";
+        let rows = parse_valid_csv_bytes(input, b',', b'"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(rows[0][0], Cow::Borrowed(b"a")));
+        assert!(matches!(rows[0][1], Cow::Borrowed(b"b,c")));
+        assert_eq!(rows[0][2], Cow::<[u8]>::Owned(b"say \"hi\"".to_vec()));
+        assert!(matches!(rows[0][2], Cow::Owned(_)));
+        assert_eq!(rows[1], vec![&b"1"[..], &b""[..], &b"x\ny"[..]]);
+
+        let mut rows = parse_valid_csv_bytes(b"a\n\"b", b',', b'"');
+        assert!(rows.next().unwrap().is_ok());
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::Invalid(Position { line: 1, column: 0 }, "Unterminated quoted field")
+        );
+        assert!(rows.next().is_none());
+
+        // Line breaks inside quotes count towards positions
+        let mut rows = parse_valid_csv_bytes(b"1,\"a\nb\"\n2,\"c\"d\n", b',', b'"');
+        assert!(rows.next().unwrap().is_ok());
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::invalid_at(2, 5, "Unexpected character after a closing quote")
+        );
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn test_fast_stream_valid_csv_multiline_quotes() {
+        let input = "id,note\n1,\"two\nlines\"\n2,\"three\n\nlines\"\n3,x\n";
+        let rows = fast_stream_valid_csv(Cursor::new(input), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["id", "note"],
+                vec!["1", "two\nlines"],
+                vec!["2", "three\n\nlines"],
+                vec!["3", "x"],
+            ]
+        );
+
+        let input = "a,b\n1,\"open \"\"\nstill open\n";
+        let mut rows = fast_stream_valid_csv(Cursor::new(input), ',', '"');
+        assert!(rows.next().unwrap().is_ok());
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::Invalid(Position { line: 1, column: 2 }, "Unterminated quoted field")
+        );
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn test_stream_valid_csv_skip_empty_lines() {
+        let input = "a,b\n\n1,2\r\n,\n\"\"\n\n";
+        let rows = stream_valid_csv(Cursor::new(input), &Dialect::default())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows.len(), 6);
+
+        let dialect = Dialect {
+            skip_empty_lines: true,
+            ..Dialect::default()
+        };
+        let rows = stream_valid_csv(Cursor::new(input), &dialect)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec!["a", "b"], vec!["1", "2"], vec!["", ""], vec![""]]
+        );
+    }
+
+    #[test]
+    fn test_stream_valid_csv_comments() {
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let input = "# exported 2024-01-01, \"v2\n  # columns: id,tag\nid,tag\n\"#1\",x\n2,#y\n# done\n3,\"open";
+        let mut rows = stream_valid_csv(Cursor::new(input), &dialect);
+        assert_eq!(rows.next().unwrap().unwrap(), vec!["id", "tag"]);
+        assert_eq!(rows.next().unwrap().unwrap(), vec!["#1", "x"]);
+        assert_eq!(rows.next().unwrap().unwrap(), vec!["2", "#y"]);
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::Invalid(Position { line: 6, column: 2 }, "Unterminated quoted field")
+        );
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let raw =
+            b"id,name,city\n1,Ada,London\n2,Bo,Paris\n3,Cy,Rome\nsku\tqty\n10\t5\n11\t7\n12\t1\n";
+        let sections = parse_sections(raw, b",\t;", 3).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].delimiter, ',');
+        assert_eq!(sections[0].position, Position { line: 0, column: 0 });
+        assert_eq!(sections[0].rows.len(), 4);
+        assert_eq!(sections[0].rows[3], vec!["3", "Cy", "Rome"]);
+        assert_eq!(sections[1].delimiter, '\t');
+        assert_eq!(sections[1].position, Position { line: 4, column: 0 });
+        assert_eq!(
+            sections[1].rows,
+            vec![
+                vec!["sku", "qty"],
+                vec!["10", "5"],
+                vec!["11", "7"],
+                vec!["12", "1"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stream_valid_csv_trimmed() {
+        let input = " id , name ,note\n1, Ada ,  \"  spaced  \" \n 2 ,é ,\t\n";
+        let dialect = Dialect::default();
+        let rows = |trim| {
+            stream_valid_csv_trimmed(Cursor::new(input), &dialect, trim)
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(
+            rows(TrimMode::All),
+            vec![
+                vec!["id", "name", "note"],
+                vec!["1", "Ada", "  spaced  "],
+                vec!["2", "é", ""],
+            ]
+        );
+        assert_eq!(rows(TrimMode::Headers)[0], vec!["id", "name", "note"]);
+        assert_eq!(
+            rows(TrimMode::Headers)[1],
+            vec!["1", " Ada ", "    spaced   "]
+        );
+        assert_eq!(rows(TrimMode::Fields)[0], vec![" id ", " name ", "note"]);
+        assert_eq!(rows(TrimMode::Fields)[2], vec!["2", "é", ""]);
+        assert_eq!(rows(TrimMode::None)[2], vec![" 2 ", "é ", "\t"]);
+    }
+
+    #[test]
+    fn test_fast_stream_valid_csv_with_backslash_escape() {
+        let input = "a,\"say \\\"hi\\\"\",c:\\dir\n\"one\\ntwo\",\"back\\\\\",\"\"\"\"\n";
+        let rows =
+            fast_stream_valid_csv_with_escape(Cursor::new(input), ',', '"', EscapeStyle::Backslash)
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["a", "say \"hi\"", "c:\\dir"],
+                vec!["one\ntwo", "back\\", ""],
+            ]
+        );
+
+        // The same input means something else with doubled quotes
+        let doubled = fast_stream_valid_csv(Cursor::new("\"a\\\",b\n"), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(doubled, vec![vec!["a\\", "b"]]);
+
+        // An escaped quote at the end leaves the field open
+        let error = fast_stream_valid_csv_with_escape(
+            Cursor::new("x,\"a\\\"\n"),
+            ',',
+            '"',
+            EscapeStyle::Backslash,
+        )
+        .last()
+        .unwrap();
+        assert_eq!(
+            error,
+            Err(CsvError::invalid_at(0, 2, "Unterminated quoted field"))
+        );
+    }
+
+    #[test]
+    fn test_fast_stream_valid_csv_error_positions() {
+        let input: &[u8] = b"a,b\n\"x\ny\",\xc3\xa9\xff\nc,\"d\n";
+        let rows = fast_stream_valid_csv(Cursor::new(input), ',', '"').collect::<Vec<_>>();
+        assert_eq!(
+            rows[1],
+            Err(CsvError::invalid_at(2, 4, "Line is not valid UTF-8"))
+        );
+        assert_eq!(
+            rows[2],
+            Err(CsvError::invalid_at(3, 2, "Unterminated quoted field"))
+        );
+    }
+
+    #[test]
+    fn test_stream_valid_csv_cr_handling() {
+        let input = "a,b\rc,\"d\re\"\r\nf,g\n";
+        let rows = |cr_handling| {
+            let dialect = Dialect {
+                cr_handling,
+                ..Dialect::default()
+            };
+            stream_valid_csv(Cursor::new(input), &dialect)
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(
+            rows(CrHandling::Newline),
+            vec![vec!["a", "b"], vec!["c", "d\re"], vec!["f", "g"]]
+        );
+        assert_eq!(
+            rows(CrHandling::Space),
+            vec![vec!["a", "b c", "d e"], vec!["f", "g"]]
+        );
+        assert_eq!(
+            rows(CrHandling::Keep),
+            vec![vec!["a", "b\rc", "d\re"], vec!["f", "g"]]
+        );
+    }
+
+    #[test]
+    fn test_stream_valid_csv_str_delim() {
+        let input = "a||b|c||\"d||e\"\nf|||g\n1\t|\t2\n";
+        let rows = stream_valid_csv_str_delim(Cursor::new(input), "||", '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![vec!["a", "b|c", "d||e"], vec!["f", "|g"], vec!["1\t|\t2"],]
+        );
+        let rows = stream_valid_csv_str_delim(Cursor::new("x\t|\ty|z\n"), "\t|\t", '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["x", "y|z"]]);
+    }
+
+    #[test]
+    fn test_parse_records() {
+        /// Records sent as a one-byte length followed by that many bytes
+        struct LengthPrefixed<'t>(&'t [u8]);
+        impl RecordSource for LengthPrefixed<'_> {
+            fn next_record_bytes(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+                let (&length, rest) = self.0.split_first()?;
+                if rest.len() < length as usize {
+                    return Some(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                let (record, rest) = rest.split_at(length as usize);
+                self.0 = rest;
+                Some(Ok(record.to_vec()))
+            }
+        }
+
+        let framed = b"\x03a,b\x09\"c\nd\",\"e\"";
+        let rows = parse_records(LengthPrefixed(framed), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["a", "b"], vec!["c\nd", "e"]]);
+        let mut truncated = parse_records(LengthPrefixed(b"\x03a,b\x05c"), ',', '"');
+        assert!(truncated.next().unwrap().is_ok());
+        assert!(matches!(truncated.next(), Some(Err(CsvError::Io(_)))));
+
+        let input = "a,\"b\nc\"\r\nd,e\n";
+        let rows = parse_records(LineRecords::new(Cursor::new(input), '"'), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let expected = fast_stream_valid_csv(Cursor::new(input), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_fast_stream_valid_csv_strips_bom() {
+        let input: &[u8] = b"\xEF\xBB\xBFid,name\n\xEF\xBB\xBF1,Ada\n";
+        let rows = fast_stream_valid_csv(Cursor::new(input), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["id", "name"], vec!["\u{feff}1", "Ada"]]);
+
+        let dialect = Dialect {
+            strip_bom: false,
+            ..Dialect::default()
+        };
+        let rows = stream_valid_csv(Cursor::new(input), &dialect)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows[0], vec!["\u{feff}id", "name"]);
+
+        for short in [&b"\xEF"[..], b"\xEF\xBB", b"a", b"\xEF\xBB\xBF"] {
+            let rows = fast_stream_valid_csv(Cursor::new(short), ',', '"').collect::<Vec<_>>();
+            assert!(rows.len() <= 1);
+        }
+    }
+
+    #[test]
+    fn test_stream_valid_csv_trim_after_quote() {
+        let input = "\"a\" , \"b\"\n  x y ,\"c\"d \n";
+        let rows = |trim_after_quote| {
+            let dialect = Dialect {
+                trim_after_quote,
+                ..Dialect::default()
+            };
+            stream_valid_csv(Cursor::new(input), &dialect)
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(rows(true), vec![vec!["a", "b"], vec!["  x y ", "cd "]]);
+        assert_eq!(rows(false), vec![vec!["a ", " b"], vec!["  x y ", "cd "]]);
+    }
+
+    #[test]
+    fn test_incremental_records() {
+        let mut records = IncrementalRecords::new(',', '"');
+        records.push(b"id,note\n1,\"par");
+        assert_eq!(
+            records.next_record(),
+            Some(Ok(vec!["id".into(), "note".into()]))
+        );
+        assert_eq!(records.next_record(), None);
+        records.push(b"tial\ntext\"\r");
+        assert_eq!(records.next_record(), None);
+        records.push(b"\n2,do");
+        assert_eq!(
+            records.next_record(),
+            Some(Ok(vec!["1".into(), "partial\ntext".into()]))
+        );
+        assert_eq!(records.next_record(), None);
+        records.push(b"ne");
+        assert_eq!(records.finish(), Some(Ok(vec!["2".into(), "done".into()])));
+
+        let mut records = IncrementalRecords::new(',', '"');
+        records.push(b"a\n\"b");
+        assert!(records.next_record().unwrap().is_ok());
+        assert_eq!(
+            records.finish(),
+            Some(Err(CsvError::invalid_at(1, 0, "Unterminated quoted field")))
         );
-        assert!(iter.next().is_none());
     }
 }
@@ -0,0 +1,126 @@
+//! Fixed-width text, where every column takes up the same number of characters
+//!
+//! There are no delimiters or quotes: each field is padded out to the width of
+//! its column, and each record is one line.
+
+use crate::errors::{CsvError, Result};
+use std::io::{BufRead, Write};
+
+/// Read fixed-width records, one line at a time
+///
+/// Each line is cut into fields of `widths` characters, and `pad` is trimmed
+/// from the end of each field. A line that stops short, as when an editor strips
+/// trailing padding, has empty fields for the columns it doesn't reach, and any
+/// characters past the last column are ignored. A `\r` before the newline is
+/// dropped.
+pub fn parse_fixed_width<R: BufRead>(
+    reader: R,
+    widths: &[usize],
+    pad: char,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    let widths = widths.to_vec();
+    reader.lines().map(move |line_result| {
+        let line = line_result?;
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        let mut chars = line.chars();
+        Ok(widths
+            .iter()
+            .map(|&width| {
+                let field = chars.by_ref().take(width).collect::<String>();
+                field.trim_end_matches(pad).to_string()
+            })
+            .collect())
+    })
+}
+
+/// Write rows as fixed-width records, one line each
+///
+/// Each field is padded with `pad` to the width of its column, in characters.
+/// A field wider than its column is cut short if `truncate` is set, and is
+/// `CsvError::Invalid` at its row and column otherwise. So is a row without
+/// exactly one field per width, or a field with a newline in it, since neither
+/// could be read back.
+pub fn write_fixed_width<W: Write>(
+    mut writer: W,
+    rows: &[Vec<String>],
+    widths: &[usize],
+    pad: char,
+    truncate: bool,
+) -> Result<()> {
+    let mut padding = [0; 4];
+    let padding = pad.encode_utf8(&mut padding).as_bytes();
+    for (line, row) in rows.iter().enumerate() {
+        if row.len() != widths.len() {
+            return Err(CsvError::invalid_at(
+                line,
+                row.len().min(widths.len()),
+                "Row does not have one field per column width",
+            ));
+        }
+        for (column, (field, &width)) in row.iter().zip(widths).enumerate() {
+            if field.contains(['\r', '\n']) {
+                return Err(CsvError::invalid_at(
+                    line,
+                    column,
+                    "Field contains a newline",
+                ));
+            }
+            let length = field.chars().count();
+            let field = match field.char_indices().nth(width) {
+                Some(_) if !truncate => {
+                    return Err(CsvError::invalid_at(
+                        line,
+                        column,
+                        "Field is wider than its column",
+                    ));
+                }
+                Some((end, _)) => &field[..end],
+                None => field,
+            };
+            writer.write_all(field.as_bytes())?;
+            for _ in length.min(width)..width {
+                writer.write_all(padding)?;
+            }
+        }
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_fixed_width() {
+        let rows = vec![
+            vec!["id".to_string(), "name".to_string()],
+            vec!["1".to_string(), "Zoë".to_string()],
+        ];
+        let mut output = Vec::new();
+        write_fixed_width(&mut output, &rows, &[3, 5], ' ', false).unwrap();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "id name \n1  Zoë  \n"
+        );
+        let read_back = parse_fixed_width(Cursor::new(output), &[3, 5], ' ')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read_back, rows);
+    }
+
+    #[test]
+    fn test_write_fixed_width_too_wide() {
+        let rows = vec![vec!["abcdef".to_string()]];
+        let mut output = Vec::new();
+        assert_eq!(
+            write_fixed_width(&mut output, &rows, &[4], '.', false),
+            Err(CsvError::invalid_at(0, 0, "Field is wider than its column"))
+        );
+        let mut output = Vec::new();
+        write_fixed_width(&mut output, &rows, &[4], '.', true).unwrap();
+        assert_eq!(output, b"abcd\n");
+    }
+}
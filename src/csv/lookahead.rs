@@ -0,0 +1,82 @@
+//! Read lines with a bounded window of upcoming lines available
+//!
+//! Repairs such as rejoining a field split across lines need to see what comes
+//! next before deciding what to do with the current line. This keeps that
+//! buffering in one place, and keeps track of the original line numbers so
+//! errors still point at the right place.
+
+use crate::errors::Result;
+use std::collections::VecDeque;
+use std::io::{BufRead, Lines};
+
+/// A line reader that can look up to a fixed number of lines ahead
+pub struct LookaheadReader<R> {
+    lines: Lines<R>,
+    buffer: VecDeque<Result<String>>,
+    lookahead: usize,
+    line: usize,
+}
+impl<R: BufRead> LookaheadReader<R> {
+    /// Create a reader that can peek at up to `lookahead` upcoming lines
+    pub fn new(reader: R, lookahead: usize) -> Self {
+        Self {
+            lines: reader.lines(),
+            buffer: VecDeque::with_capacity(lookahead + 1),
+            lookahead,
+            line: 0,
+        }
+    }
+
+    /// The zero-based number of the line that `next` will return
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Look at an upcoming line without consuming it
+    ///
+    /// An offset of 0 is the line `next` will return. Returns `None` at the end of
+    /// the input, or if the offset is beyond the lookahead.
+    pub fn peek(&mut self, offset: usize) -> Option<&Result<String>> {
+        if offset >= self.lookahead.max(1) {
+            return None;
+        }
+        while self.buffer.len() <= offset {
+            let line = self.lines.next()?;
+            self.buffer.push_back(line.map_err(Into::into));
+        }
+        self.buffer.get(offset)
+    }
+}
+impl<R: BufRead> Iterator for LookaheadReader<R> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.buffer.pop_front() {
+            Some(line) => line,
+            None => self.lines.next()?.map_err(Into::into),
+        };
+        self.line += 1;
+        Some(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_lookahead_reader() {
+        let mut reader = LookaheadReader::new(Cursor::new("a\nb\nc\n"), 2);
+        assert_eq!(reader.peek(1).unwrap().as_deref(), Ok("b"));
+        assert!(reader.peek(2).is_none());
+        assert_eq!(reader.next().unwrap().unwrap(), "a");
+        assert_eq!(reader.line(), 1);
+        assert_eq!(reader.peek(1).unwrap().as_deref(), Ok("c"));
+        assert_eq!(reader.next().unwrap().unwrap(), "b");
+        assert_eq!(reader.next().unwrap().unwrap(), "c");
+        assert_eq!(reader.line(), 3);
+        assert!(reader.peek(0).is_none());
+        assert!(reader.next().is_none());
+    }
+}
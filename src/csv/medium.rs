@@ -4,17 +4,26 @@
 //! - Cannot handle missing delimiters or newlines
 //! - It doesn't handle carriage returns (e.g. \r\n) as newlines correctly
 //!   - This is a problem particularly when the last column is quoted
-//! - Requires whole columns must be quoted or unquoted, not mixed
+//! - Quotes only count at the start or end of a cell, never in the middle
 //!   (e.g. a,b"c,d"e,f) can be no more than 4 columns: [ "a", "b\"c", "d\"e", "f"]
+//!   - Each cell is quoted or not on its own, so a column may mix both,
+//!     as Excel does when it only quotes the fields that need it
 //! - Requires either:
 //!   - a fixed number of columns you specify before parsing
 //!   - or a valid header row, with no unquoted delimiters or newlines
 //! - Memory complexity: O(n) space on account of tracking all delimiters, quotes, and newlines
 //! - Time complexity: O(2^n) where n is the number of cells.
-//!   - But the search can be terminated, so the worst case is a timeout
+//!   - But the search stops when its [`SolveBudget`] runs out, so the worst case is a timeout
 //!   - The more likely outcome is O(nm) where m is the number of invalid special characters
 
+use crate::csv::detect::best_delimiter;
+use crate::csv::dialect::CrHandling;
+use crate::errors::{CsvError, Result, Warning};
+use crate::Position;
 use bitvec::prelude::*;
+use std::borrow::Cow;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 /// The properties of data contained in a column
 ///
@@ -50,6 +59,17 @@ impl CharacterClass {
             _ => CharacterClass::Other,
         }
     }
+
+    /// Classify a byte, with a lone carriage return read as the dialect says
+    ///
+    /// [`CharacterClass::from_byte`] always calls `\r` a newline, which is only
+    /// right for [`CrHandling::Newline`]. Otherwise it is whitespace.
+    pub fn from_byte_with(byte: u8, cr_handling: CrHandling) -> CharacterClass {
+        match byte {
+            b'\r' if cr_handling != CrHandling::Newline => CharacterClass::Whitespace,
+            _ => CharacterClass::from_byte(byte),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Default, Eq, PartialOrd, Ord)]
@@ -75,8 +95,13 @@ impl ColumnComplexity {
 
     /// Recalculate the column complexity after adding some bytes
     pub fn add_bytes(&mut self, bytes: &[u8]) {
+        self.add_bytes_with(bytes, CrHandling::Newline);
+    }
+
+    /// Add some bytes, with a lone carriage return classified as the dialect says
+    fn add_bytes_with(&mut self, bytes: &[u8], cr_handling: CrHandling) {
         for byte in bytes {
-            self.class_counts[CharacterClass::from_byte(*byte) as usize] += 1;
+            self.class_counts[CharacterClass::from_byte_with(*byte, cr_handling) as usize] += 1;
         }
     }
 
@@ -87,8 +112,12 @@ impl ColumnComplexity {
     /// an even distribution of classes has a gini impurity of 1.
     /// We want to minimize the gini impurity of each column, since that indicates
     /// that the data is more likely to be valid.
+    /// An empty column has no impurity.
     pub fn gini_impurity(&self) -> f64 {
         let total = self.class_counts.iter().sum::<usize>() as f64;
+        if total == 0.0 {
+            return 0.0;
+        }
         let mut sum = 0.0;
         for count in self.class_counts.iter() {
             let p = *count as f64 / total;
@@ -96,15 +125,170 @@ impl ColumnComplexity {
         }
         1.0 - sum
     }
+
+    /// The least gini impurity this column could have after gaining `remaining` more bytes
+    ///
+    /// The impurity is lowest if every new byte is in the most common class, and
+    /// it only falls as more such bytes are added, so this is a lower bound
+    /// however many of the remaining bytes actually end up in the column.
+    fn gini_lower_bound(&self, remaining: usize) -> f64 {
+        let total = (self.len() + remaining) as f64;
+        if total == 0.0 {
+            return 0.0;
+        }
+        let most = self.class_counts.iter().copied().max().unwrap_or(0);
+        let mut sum = 0.0;
+        for &count in &self.class_counts {
+            let p = count as f64 / total;
+            sum += p * p;
+        }
+        // Give the most common class the remaining bytes
+        let p = most as f64 / total;
+        let q = (most + remaining) as f64 / total;
+        1.0 - (sum - p * p + q * q)
+    }
+
+    /// Calculate the Shannon entropy of this column, in bits
+    ///
+    /// Like the gini impurity, this is 0 for a column of one class and grows as
+    /// the classes even out, but it weighs rare classes more heavily.
+    /// An empty column has no entropy.
+    pub fn entropy(&self) -> f64 {
+        let total = self.len() as f64;
+        self.class_counts
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / total;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Count how many classes appear in this column at all
+    pub fn presence(&self) -> usize {
+        self.class_counts.iter().filter(|&&count| count > 0).count()
+    }
+
+    /// How many bytes the column has
+    fn len(&self) -> usize {
+        self.class_counts.iter().sum()
+    }
+
+    /// Add the bytes of another column to this one
+    fn merge(&mut self, other: &ColumnComplexity) {
+        for (count, other) in self.class_counts.iter_mut().zip(other.class_counts) {
+            *count += other;
+        }
+    }
+
+    /// Take away the bytes of another column, which must be part of this one
+    fn unmerge(&mut self, other: &ColumnComplexity) {
+        for (count, other) in self.class_counts.iter_mut().zip(other.class_counts) {
+            *count -= other;
+        }
+    }
 }
 
-type Mask = BitVec<u64, Lsb0>;
+/// Limits on how long [`Solution::solve`] searches before giving up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SolveBudget {
+    /// How many changes to try
+    pub max_steps: u64,
+    /// How long to search for, if there is a time limit as well
+    pub max_duration: Option<Duration>,
+}
+impl Default for SolveBudget {
+    fn default() -> Self {
+        Self {
+            max_steps: 100_000,
+            max_duration: None,
+        }
+    }
+}
+
+/// How much searching [`Solution::solve`] did, for tuning its [`SolveBudget`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SolveStats {
+    /// How many changes were tried, which is what [`SolveBudget::max_steps`] limits
+    pub iterations: u64,
+    /// How many of those changes were to a quote, pairing or unpairing it
+    pub quote_pairs_considered: u64,
+    /// Whether the search stopped because the budget ran out
+    pub budget_hit: bool,
+}
+
+/// How sure the solver has to be of a solution not to warn about it
+///
+/// See [`Solution::with_confidence_threshold`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfidenceThreshold {
+    /// The least the best single change may add to the solution's score
+    pub min_margin: f64,
+    /// The most [`Solution::mean_impurity`] a solution may have
+    pub max_mean_impurity: f64,
+}
+
+/// A measure of how messy a column is, for [`Solution::solve_with`] to minimise
+///
+/// The solver adds up the scores of all the columns, so lower must mean more
+/// consistent.
+pub trait ColumnObjective {
+    /// Score one column
+    fn score(&self, column: &ColumnComplexity) -> f64;
+
+    /// The least score a column could have after gaining `remaining` more bytes
+    ///
+    /// This lets the solver abandon a change early, so it has to be a true lower
+    /// bound or the solver may miss better changes. The default never abandons one.
+    fn lower_bound(&self, _column: &ColumnComplexity, _remaining: usize) -> f64 {
+        f64::NEG_INFINITY
+    }
+}
+
+/// The gini impurity, which [`Solution::solve`] uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GiniObjective;
+impl ColumnObjective for GiniObjective {
+    fn score(&self, column: &ColumnComplexity) -> f64 {
+        column.gini_impurity()
+    }
+
+    fn lower_bound(&self, column: &ColumnComplexity, remaining: usize) -> f64 {
+        column.gini_lower_bound(remaining)
+    }
+}
+
+/// The Shannon entropy, which weighs rare classes more heavily than gini impurity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EntropyObjective;
+impl ColumnObjective for EntropyObjective {
+    fn score(&self, column: &ColumnComplexity) -> f64 {
+        column.entropy()
+    }
+
+    fn lower_bound(&self, _column: &ColumnComplexity, _remaining: usize) -> f64 {
+        // Entropy is never negative
+        0.0
+    }
+}
+
+/// How many classes appear in a column at all, however rarely
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PresenceObjective;
+impl ColumnObjective for PresenceObjective {
+    fn score(&self, column: &ColumnComplexity) -> f64 {
+        column.presence() as f64
+    }
 
-pub struct Switches {
-    pub delimiter_valid: Mask,
-    pub quote_valid: Mask,
+    fn lower_bound(&self, column: &ColumnComplexity, _remaining: usize) -> f64 {
+        // More bytes can only add classes
+        column.presence() as f64
+    }
 }
 
+type Mask = BitVec<u64, Lsb0>;
+
 #[derive(Debug, PartialEq, Clone, Default)]
 pub struct Solution {
     delimiter: u8,
@@ -112,9 +296,19 @@ pub struct Solution {
     column_complexities: Vec<ColumnComplexity>,
     file_length: usize,
     delimiter_locations: Vec<usize>,
+    delimiter_valid: Mask,
+    delimiter_is_newline: Mask,
     quote_locations: Vec<usize>,
+    quote_valid: Mask,
     quote_can_start: Mask,
     quote_can_end: Mask,
+    /// The delimiters and newlines fixed by hand, which the solver leaves alone
+    pinned: Mask,
+    cr_handling: CrHandling,
+    has_quotes: bool,
+    stats: SolveStats,
+    confidence: Option<ConfidenceThreshold>,
+    warnings: Vec<Warning>,
 }
 impl Solution {
     /// Create a new default solution
@@ -123,56 +317,278 @@ impl Solution {
     /// the purpose of this initial parse is to provide a starting point for the
     /// solver.
     pub fn new(raw: &[u8], delimiter: u8) -> Self {
-        let mut this = Self::default();
-        this.delimiter = delimiter;
+        let mut this = Self {
+            delimiter,
+            ..Self::default()
+        };
         for (i, byte) in raw.iter().enumerate() {
             match byte {
                 b'\n' => {
                     this.delimiter_locations.push(i);
                 }
-                b'"' => {
-                    if i == 0
-                        || raw[i - 1] == delimiter
-                        || raw[i - 1] == b'\n'
-                        || raw[i - 1..].starts_with(b"\r\n")
-                        || i == raw.len() - 1
-                    {
-                        // Quotes are only allowed next to a delimiter, a carriage return, or the start/end of the file
-                        this.quote_locations.push(i);
-                    }
+                // Quotes are only allowed next to a delimiter, a newline, or the start/end of the file
+                b'"' if i == 0
+                    || raw[i - 1] == delimiter
+                    || raw[i - 1] == b'\n'
+                    || i == raw.len() - 1
+                    || raw[i + 1] == delimiter
+                    || raw[i + 1] == b'\n'
+                    || raw[i + 1..].starts_with(b"\r\n") =>
+                {
+                    this.quote_locations.push(i);
                 }
+                b'"' => {}
                 _ if *byte == delimiter => {
                     this.delimiter_locations.push(i);
                 }
                 _ => {}
             }
         }
+        // Every delimiter and quote starts out valid, for the solver to rule out
+        this.delimiter_valid = bitvec![u64, Lsb0; 1; this.delimiter_locations.len()];
+        this.quote_valid = bitvec![u64, Lsb0; 1; this.quote_locations.len()];
+        this.pinned = bitvec![u64, Lsb0; 0; this.delimiter_locations.len()];
+        this.delimiter_is_newline = this
+            .delimiter_locations
+            .iter()
+            .map(|&i| raw[i] == b'\n')
+            .collect();
         this.quote_can_start = this.quote_valid.clone();
         this.quote_can_end = this.quote_valid.clone();
         this.file_length = raw.len();
+        this.has_quotes = !this.quote_locations.is_empty();
         this.default_heuristics();
         this
     }
 
+    /// Create a new default solution, detecting the delimiter first
+    ///
+    /// The delimiter is chosen from `candidates` with [`best_delimiter`], and is
+    /// available afterwards from [`Solution::delimiter`].
+    pub fn with_detection(raw: &[u8], candidates: &[u8]) -> Result<Self> {
+        let delimiter = best_delimiter(raw, candidates).ok_or(CsvError::invalid_at(
+            0,
+            0,
+            "None of the candidate delimiters split the input into columns",
+        ))?;
+        Ok(Self::new(raw, delimiter))
+    }
+
+    /// Create a new default solution, optionally refusing to guess
+    ///
+    /// In strict mode, any quote that could still either start or end a cell after
+    /// the default heuristics is reported as `CsvError::Ambiguity` at that quote,
+    /// rather than being left for the solver. This makes the parser a conformance
+    /// checker. Otherwise this is the same as [`Solution::new`].
+    pub fn with_strict_mode(raw: &[u8], delimiter: u8, strict: bool) -> Result<Self> {
+        let this = Self::new(raw, delimiter);
+        if strict {
+            let ambiguous = (0..this.quote_locations.len()).find(|&quote_num| {
+                this.quote_valid[quote_num]
+                    && this.quote_can_start[quote_num]
+                    && this.quote_can_end[quote_num]
+            });
+            if let Some(quote_num) = ambiguous {
+                return Err(CsvError::Ambiguity(
+                    position_of(raw, this.quote_locations[quote_num]),
+                    "Quote could either start or end a cell",
+                ));
+            }
+        }
+        Ok(this)
+    }
+
+    /// Set the number of columns every row must have
+    ///
+    /// Without a column count, every valid newline ends a row. With one, newlines
+    /// are treated like any other delimiter and rows are made of exactly this many
+    /// cells, which is what lets the solver repair unescaped newlines.
+    pub fn with_column_count(mut self, column_count: usize) -> Self {
+        self.column_count = Some(column_count);
+        self
+    }
+
+    /// Choose what a carriage return inside a cell means
+    ///
+    /// This parser never ends a row at a lone `\r`, but it still has to decide
+    /// what kind of character it is. As a [`CrHandling::Newline`], the default, it
+    /// counts as a newline when the columns are scored. Otherwise it counts as
+    /// whitespace, and with [`CrHandling::Space`] cells have it replaced by a
+    /// space as well.
+    pub fn with_cr_handling(mut self, cr_handling: CrHandling) -> Self {
+        self.cr_handling = cr_handling;
+        self
+    }
+
+    /// Warn when the solver isn't sure of its solution
+    ///
+    /// After a search that finishes, the solution is checked against the
+    /// threshold, and [`Solution::warnings`] says where it falls short: if some
+    /// single change would raise the score by less than the margin, the warning
+    /// is at that change, and if the columns are too mixed it is at the start of
+    /// the input. The solution is still the best one found either way, so a
+    /// pipeline can keep it and send it for review.
+    pub fn with_confidence_threshold(mut self, threshold: ConfidenceThreshold) -> Self {
+        self.confidence = Some(threshold);
+        self
+    }
+
+    /// Force the newline at `offset` to end a record
+    ///
+    /// This is for reviewers fixing a row by hand: the newline is marked valid so
+    /// it breaks the cells around it, whatever the heuristics decided, and the
+    /// solver never changes it back. If there is no newline there, the error is
+    /// at `offset`.
+    pub fn pin_newline(&mut self, offset: usize) -> Result<()> {
+        let delimiter_num = self.newline_index(offset)?;
+        self.delimiter_valid.set(delimiter_num, true);
+        self.pinned.set(delimiter_num, true);
+        Ok(())
+    }
+
+    /// Forbid the newline at `offset` from ending a record
+    ///
+    /// The newline becomes part of the cell around it, as if it were quoted, and
+    /// like [`Solution::pin_newline`] this holds however the solver searches.
+    pub fn unpin_newline(&mut self, offset: usize) -> Result<()> {
+        let delimiter_num = self.newline_index(offset)?;
+        self.delimiter_valid.set(delimiter_num, false);
+        self.pinned.set(delimiter_num, true);
+        Ok(())
+    }
+
+    /// Find which delimiter is the newline at `offset`
+    fn newline_index(&self, offset: usize) -> Result<usize> {
+        self.delimiter_locations
+            .binary_search(&offset)
+            .ok()
+            .filter(|&delimiter_num| self.delimiter_is_newline[delimiter_num])
+            .ok_or_else(|| {
+                CsvError::Invalid(
+                    self.position_at(offset),
+                    "There is no newline at this offset",
+                )
+            })
+    }
+
+    /// The line and column of a byte, found from the newlines among the delimiters
+    fn position_at(&self, offset: usize) -> Position {
+        let before = self
+            .delimiter_locations
+            .partition_point(|&d_byte| d_byte < offset);
+        let newlines = (0..before).filter(|&d_ix| self.delimiter_is_newline[d_ix]);
+        let line_start = newlines
+            .clone()
+            .next_back()
+            .map_or(0, |d_ix| self.delimiter_locations[d_ix] + 1);
+        Position {
+            line: newlines.count(),
+            column: offset - line_start,
+        }
+    }
+
+    /// Whether the raw input has any quotes that could start or end a cell
+    ///
+    /// If not, there is no quote pairing to search, and the solution is plain
+    /// delimiter splitting.
+    pub fn has_quotes(&self) -> bool {
+        self.has_quotes
+    }
+
+    /// The delimiter this solution splits cells on
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// The warnings from the last call to [`Solution::solve`]
+    ///
+    /// There are only ever any with a confidence threshold set.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// How much searching the last call to [`Solution::solve`] did
+    ///
+    /// Before the solver has run, nothing has been tried.
+    pub fn stats(&self) -> SolveStats {
+        self.stats
+    }
+
+    /// Count the delimiters inside quoted cells
+    ///
+    /// These are the delimiters a naive split would break a cell on, so a high
+    /// count means the file really needs a quote-aware parser. Newlines inside
+    /// quoted cells aren't counted.
+    pub fn protected_delimiter_count(&self) -> usize {
+        self.iter_quote_pairs(0)
+            .map(|(start_byte, end_byte)| {
+                let first = self
+                    .delimiter_locations
+                    .partition_point(|&d_byte| d_byte < start_byte);
+                let last = self
+                    .delimiter_locations
+                    .partition_point(|&d_byte| d_byte < end_byte);
+                (first..last)
+                    .filter(|&delimiter_num| !self.delimiter_is_newline[delimiter_num])
+                    .count()
+            })
+            .sum()
+    }
+
+    /// The gini impurity of every column, weighted by its length in bytes, summed
+    ///
+    /// This is a sum, so it grows with the file; use [`Solution::mean_impurity`]
+    /// to compare files of different sizes. Weighting by length keeps a short
+    /// column from counting as much as a long one, which the solver's own score
+    /// doesn't do. The columns are as measured by the last call to
+    /// [`Solution::solve`], so this is zero before the solver has run.
+    pub fn total_impurity(&self) -> f64 {
+        self.column_complexities
+            .iter()
+            .map(|column| column.gini_impurity() * column.len() as f64)
+            .sum()
+    }
+
+    /// The gini impurity of every column, weighted by its length in bytes, averaged
+    ///
+    /// This is [`Solution::total_impurity`] divided by the bytes in all the
+    /// columns, so it is between 0 and 1 whatever the size of the file.
+    pub fn mean_impurity(&self) -> f64 {
+        let total = self
+            .column_complexities
+            .iter()
+            .map(ColumnComplexity::len)
+            .sum::<usize>();
+        if total == 0 {
+            return 0.0;
+        }
+        self.total_impurity() / total as f64
+    }
+
     /// Apply the default heuristics to this solution
     /// in order to give the solver a better starting point
     fn default_heuristics(&mut self) {
-        // Quotes can only be valid if they are preceded by or followed by a valid delimiter
+        if !self.has_quotes {
+            return;
+        }
+        // Quotes can only be valid if they are preceded by or followed by a valid delimiter.
+        // Both location lists are sorted, so walk them together rather than searching.
+        let mut delimiters = self.delimiter_locations.iter().peekable();
         for (quote_num, &quote_byte) in self.quote_locations.iter().enumerate() {
-            let prev = quote_byte == 0
-                || self
-                    .delimiter_locations
-                    .binary_search(&(quote_byte - 1))
-                    .is_ok();
-            let next = quote_byte == self.file_length - 1
-                || self
-                    .delimiter_locations
-                    .binary_search(&(quote_byte + 1))
-                    .is_ok();
-            if prev {
+            // Skip delimiters too early to touch this quote, or any quote after it
+            while delimiters
+                .next_if(|&&delimiter_byte| delimiter_byte + 1 < quote_byte)
+                .is_some()
+            {}
+            // The quote itself isn't a delimiter, so only the next two can be adjacent
+            let mut adjacent = delimiters.clone();
+            let prev = quote_byte == 0 || adjacent.next_if_eq(&&(quote_byte - 1)).is_some();
+            let next =
+                quote_byte == self.file_length - 1 || adjacent.next() == Some(&(quote_byte + 1));
+            if !prev {
                 self.quote_can_start.set(quote_num, false);
             }
-            if next {
+            if !next {
                 self.quote_can_end.set(quote_num, false);
             }
         }
@@ -189,50 +605,1634 @@ impl Solution {
         //
     }
 
+    /// Search for the solution with the least total gini impurity
+    ///
+    /// Starting from the heuristics, each round tries turning every delimiter,
+    /// newline and quote that could start or end a cell from valid to invalid or
+    /// back, keeps whichever single change lowers the score the most, and stops
+    /// when none do. Each change tried is one step.
+    ///
+    /// A change can only move cells between the row boundary before it and the
+    /// next row boundary the two solutions share, so only that stretch is measured.
+    /// The search is also a branch and bound: after each row of the stretch, the
+    /// columns so far get a lower bound on their final score, and the change is
+    /// abandoned as soon as it can't beat the best change of the round.
+    ///
+    /// If the budget runs out first, the search keeps the best change of the
+    /// round so far and stops with `CsvError::Ambiguity` at the character it was
+    /// about to change. The solution is still usable then; it just might not be
+    /// the best the search would have found. Either way, [`Solution::stats`] says
+    /// how much searching was done.
+    pub fn solve(&mut self, raw: &[u8], budget: SolveBudget) -> Result<()> {
+        self.solve_with(raw, budget, &GiniObjective)
+    }
+
+    /// Search for the solution with the least total score under any objective
+    ///
+    /// This is [`Solution::solve`] with the gini impurity swapped out. The branch
+    /// and bound only prunes as well as the objective's lower bound allows.
+    pub fn solve_with(
+        &mut self,
+        raw: &[u8],
+        budget: SolveBudget,
+        objective: &dyn ColumnObjective,
+    ) -> Result<()> {
+        let deadline = budget
+            .max_duration
+            .map(|duration| Instant::now() + duration);
+        let flips = self.flips();
+        let mut stats = SolveStats::default();
+        self.warnings.clear();
+        loop {
+            self.update_complexities(raw);
+            let mut candidate = self.clone();
+            let mut best = None;
+            let mut best_score = total_score(&self.column_complexities, objective);
+            let mut exhausted = None;
+            // A change after an unpaired quote could pair it, moving every row after it
+            let unpaired = self.unpaired_quote().unwrap_or(usize::MAX);
+            let mut rows = self.iter_row_spans().peekable();
+            let mut prefix = Vec::new();
+            let mut row_start = 0;
+            for &flip in &flips {
+                let out_of_time = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if stats.iterations == budget.max_steps || out_of_time {
+                    exhausted = Some(flip);
+                    break;
+                }
+                stats.iterations += 1;
+                if let Flip::Quote(_) = flip {
+                    stats.quote_pairs_considered += 1;
+                }
+                let location = self.flip_location(flip);
+                while let Some(row) = rows.next_if(|row| row_end(row) <= location.min(unpaired)) {
+                    row_start = row_end(&row);
+                    add_row(&mut prefix, &row, raw, self.cr_handling);
+                }
+                candidate.flip(flip);
+                let stretch = Stretch {
+                    prefix: &prefix,
+                    row_start,
+                    location,
+                };
+                let score = candidate.measure_change(self, raw, stretch, objective, best_score);
+                candidate.flip(flip);
+                if let Some(score) = score.filter(|&score| score < best_score) {
+                    best = Some(flip);
+                    best_score = score;
+                }
+            }
+            drop(rows);
+            if let Some(best) = best {
+                self.flip(best);
+            }
+            if let Some(flip) = exhausted {
+                self.update_complexities(raw);
+                stats.budget_hit = true;
+                self.stats = stats;
+                return Err(CsvError::Ambiguity(
+                    position_of(raw, self.flip_location(flip)),
+                    "Solver ran out of budget before finding the best solution",
+                ));
+            }
+            if best.is_none() {
+                self.stats = stats;
+                self.warnings = self.check_confidence(raw, objective);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Warn where this solution falls short of the confidence threshold
+    ///
+    /// The columns have to be measured already.
+    fn check_confidence(&self, raw: &[u8], objective: &dyn ColumnObjective) -> Vec<Warning> {
+        let Some(threshold) = self.confidence else {
+            return Vec::new();
+        };
+        let mut warnings = Vec::new();
+        let score = total_score(&self.column_complexities, objective);
+        let runner_up = self
+            .score_flips(raw, objective)
+            .into_iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some((runner_up, _, flip)) = runner_up {
+            if runner_up - score < threshold.min_margin {
+                warnings.push(Warning {
+                    position: position_of(raw, self.flip_location(flip)),
+                    message: "Another interpretation scores almost as well",
+                });
+            }
+        }
+        if self.mean_impurity() > threshold.max_mean_impurity {
+            warnings.push(Warning {
+                position: position_of(raw, 0),
+                message: "Columns are too mixed to be sure of the solution",
+            });
+        }
+        warnings
+    }
+
+    /// Every change the solver can make, in file order
+    ///
+    /// In file order, the rows before each change only need to be measured once.
+    /// Pinned newlines are left out, so no change can undo them.
+    fn flips(&self) -> Vec<Flip> {
+        let mut flips = (0..self.delimiter_locations.len())
+            .filter(|&delimiter_num| !self.pinned[delimiter_num])
+            .map(Flip::Delimiter)
+            .chain(
+                (0..self.quote_locations.len())
+                    .filter(|&quote_num| {
+                        self.quote_can_start[quote_num] || self.quote_can_end[quote_num]
+                    })
+                    .map(Flip::Quote),
+            )
+            .collect::<Vec<_>>();
+        flips.sort_by_key(|&flip| self.flip_location(flip));
+        flips
+    }
+
+    /// Score every change to this solution on its own
+    ///
+    /// Each change comes with the score after it and how many quote pairs there
+    /// are then. The columns have to be measured already.
+    fn score_flips(&self, raw: &[u8], objective: &dyn ColumnObjective) -> Vec<(f64, usize, Flip)> {
+        let mut candidate = self.clone();
+        let unpaired = self.unpaired_quote().unwrap_or(usize::MAX);
+        let mut rows = self.iter_row_spans().peekable();
+        let mut prefix = Vec::new();
+        let mut row_start = 0;
+        let mut scores = Vec::new();
+        for flip in self.flips() {
+            let location = self.flip_location(flip);
+            while let Some(row) = rows.next_if(|row| row_end(row) <= location.min(unpaired)) {
+                row_start = row_end(&row);
+                add_row(&mut prefix, &row, raw, self.cr_handling);
+            }
+            candidate.flip(flip);
+            let stretch = Stretch {
+                prefix: &prefix,
+                row_start,
+                location,
+            };
+            let score = candidate.measure_change(self, raw, stretch, objective, f64::INFINITY);
+            if let Some(score) = score {
+                scores.push((score, candidate.iter_quote_pairs(0).count(), flip));
+            }
+            candidate.flip(flip);
+        }
+        scores
+    }
+
+    /// Score this solution, which differs from `current` by one change
+    ///
+    /// Rows before `stretch.row_start` are the same in both, and are measured in
+    /// `stretch.prefix`. Rows are read from there in both solutions until they end
+    /// at the same place after the change, and from then on they are the same
+    /// again, so the score is the current columns with the stretch swapped out.
+    /// Returns `None` as soon as the score can't be below `cutoff`.
+    fn measure_change(
+        &self,
+        current: &Solution,
+        raw: &[u8],
+        stretch: Stretch,
+        objective: &dyn ColumnObjective,
+        cutoff: f64,
+    ) -> Option<f64> {
+        let mut columns = stretch.prefix.to_vec();
+        let mut replaced = Vec::new();
+        let mut rows = self.iter_row_spans_from(stretch.row_start);
+        let mut current_rows = current.iter_row_spans_from(stretch.row_start);
+        let mut end = stretch.row_start;
+        let mut current_end = stretch.row_start;
+        loop {
+            if end == current_end && end > stretch.location {
+                // Back in step, so the rest of the current columns still apply
+                for (column, total) in current.column_complexities.iter().enumerate() {
+                    let mut rest = total.clone();
+                    if let Some(prefix) = stretch.prefix.get(column) {
+                        rest.unmerge(prefix);
+                    }
+                    if let Some(replaced) = replaced.get(column) {
+                        rest.unmerge(replaced);
+                    }
+                    add_column(&mut columns, column, &rest);
+                }
+                break;
+            }
+            if end <= current_end {
+                let Some(row) = rows.next() else {
+                    break;
+                };
+                end = row_end(&row);
+                add_row(&mut columns, &row, raw, self.cr_handling);
+                let remaining = self.file_length - end;
+                let bound = columns
+                    .iter()
+                    .map(|column| objective.lower_bound(column, remaining))
+                    .sum::<f64>();
+                if bound >= cutoff {
+                    return None;
+                }
+            } else {
+                match current_rows.next() {
+                    Some(row) => {
+                        current_end = row_end(&row);
+                        add_row(&mut replaced, &row, raw, self.cr_handling);
+                    }
+                    None => current_end = usize::MAX,
+                }
+            }
+        }
+        Some(total_score(&columns, objective))
+    }
+
+    /// Measure the columns again, as the rows now split
+    fn update_complexities(&mut self, raw: &[u8]) {
+        let mut columns = Vec::new();
+        for row in self.iter_row_spans() {
+            add_row(&mut columns, &row, raw, self.cr_handling);
+        }
+        self.column_complexities = columns;
+    }
+
+    /// Turn one delimiter or quote from valid to invalid, or back
+    fn flip(&mut self, flip: Flip) {
+        let (mask, num) = match flip {
+            Flip::Delimiter(delimiter_num) => (&mut self.delimiter_valid, delimiter_num),
+            Flip::Quote(quote_num) => (&mut self.quote_valid, quote_num),
+        };
+        let valid = mask[num];
+        mask.set(num, !valid);
+    }
+
+    /// The byte offset of the character a flip changes
+    fn flip_location(&self, flip: Flip) -> usize {
+        match flip {
+            Flip::Delimiter(delimiter_num) => self.delimiter_locations[delimiter_num],
+            Flip::Quote(quote_num) => self.quote_locations[quote_num],
+        }
+    }
+
     /// Iterate over quote pairs in the solution in order
-    fn iter_quote_pairs<'t>(&'t self) -> impl Iterator<Item = (usize, usize)> + 't {
-        let mut quotes = self.quote_locations.iter().enumerate().peekable();
+    ///
+    /// A pair opens at a quote that can start a cell and closes at the next quote
+    /// that can end one, so whether a cell is quoted is decided cell by cell.
+    ///
+    /// Only quotes from `from` on are paired, as if the file started there.
+    fn iter_quote_pairs(&self, from: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let first = self
+            .quote_locations
+            .partition_point(|&q_byte| q_byte < from);
+        let mut quotes = self.quote_locations.iter().enumerate().skip(first);
 
         std::iter::from_fn(move || {
-            let quotes = quotes.by_ref();
             let start_byte = quotes
-                .skip_while(|(q_ix, _q_byte)| {
-                    !self.quote_can_start[*q_ix] || !self.quote_valid[*q_ix]
-                })
-                .next()?
+                .find(|(q_ix, _q_byte)| self.quote_can_start[*q_ix] && self.quote_valid[*q_ix])?
                 .1;
             let end_byte = quotes
-                .skip_while(|(q_ix, _q_byte)| {
-                    !self.quote_can_end[*q_ix] || !self.quote_valid[*q_ix]
-                })
-                .next()?
+                .find(|(q_ix, _q_byte)| self.quote_can_end[*q_ix] && self.quote_valid[*q_ix])?
                 .1;
             Some((*start_byte, *end_byte))
         })
     }
 
+    /// The quote that starts a pair but has nothing to end it, if there is one
+    ///
+    /// This is where [`Solution::iter_quote_pairs`] stops pairing quotes.
+    fn unpaired_quote(&self) -> Option<usize> {
+        let mut quotes = self.quote_locations.iter().enumerate();
+        loop {
+            let start_byte = quotes
+                .find(|(q_ix, _q_byte)| self.quote_can_start[*q_ix] && self.quote_valid[*q_ix])?
+                .1;
+            let end =
+                quotes.find(|(q_ix, _q_byte)| self.quote_can_end[*q_ix] && self.quote_valid[*q_ix]);
+            if end.is_none() {
+                return Some(*start_byte);
+            }
+        }
+    }
+
+    /// Iterate over the special characters in effect, in file order
+    ///
+    /// These are the valid delimiters and newlines, and both quotes of each quote
+    /// pair. Delimiters and newlines between the quotes of a pair are part of the
+    /// quoted cell, so they are skipped.
+    fn iter_specials(&self, from: usize) -> impl Iterator<Item = (usize, CharacterClass)> + '_ {
+        let delimiter_class = CharacterClass::from_byte(self.delimiter);
+        let first = self
+            .delimiter_locations
+            .partition_point(|&d_byte| d_byte < from);
+        let mut delimiters = self
+            .delimiter_locations
+            .iter()
+            .enumerate()
+            .skip(first)
+            .filter(|(d_ix, _)| self.delimiter_valid[*d_ix])
+            .map(move |(d_ix, &d_byte)| {
+                if self.delimiter_is_newline[d_ix] {
+                    (d_byte, CharacterClass::Newline)
+                } else {
+                    (d_byte, delimiter_class)
+                }
+            })
+            .peekable();
+        let mut quote_pairs = self.iter_quote_pairs(from).peekable();
+        let mut pending_end = None;
+        std::iter::from_fn(move || {
+            if let Some(end_byte) = pending_end.take() {
+                return Some((end_byte, CharacterClass::Quote));
+            }
+            match (delimiters.peek(), quote_pairs.peek()) {
+                (Some(&(d_byte, _)), Some(&(start_byte, _))) if d_byte < start_byte => {
+                    delimiters.next()
+                }
+                (_, Some(_)) => {
+                    let (start_byte, end_byte) = quote_pairs.next()?;
+                    while delimiters
+                        .next_if(|(d_byte, _)| *d_byte < end_byte)
+                        .is_some()
+                    {}
+                    pending_end = Some(end_byte);
+                    Some((start_byte, CharacterClass::Quote))
+                }
+                (_, None) => delimiters.next(),
+            }
+        })
+    }
+
     /// Iterate over all cells in the solution
-    pub fn iter_cells<'t>(&'t self, raw: &'t [u8]) -> impl Iterator<Item = &'t [u8]> + 't {
-        let mut prev_index = 0;
-        self.iter_specials()
-            .flat_map(move |(index, class)| match class {
-                CharacterClass::Comma | CharacterClass::Newline => {
-                    let cell = &raw[prev_index..index];
-                    prev_index = index + 1;
-                    cell
-                }
-                CharacterClass::Quote => {
-                    let mut cell = &raw[prev_index..index];
-                    prev_index = index + 1;
-                    if cell.starts_with(&[b'"']) {
-                        cell = &cell[1..];
+    ///
+    /// Quoted cells are returned without their quotes, and with doubled quotes
+    /// unescaped, so `a,"",b` has an empty middle cell and `""""` is a single `"`.
+    /// Cells are only copied when there is something to unescape.
+    pub fn iter_cells<'t>(&'t self, raw: &'t [u8]) -> impl Iterator<Item = Cow<'t, [u8]>> + 't {
+        self.iter_cell_spans(0)
+            .map(move |cell| cell.contents(raw, self.cr_handling))
+    }
+
+    /// Call a closure on every cell in the solution
+    ///
+    /// This is the push-style counterpart of `iter_cells`: nothing borrowed from the
+    /// solution outlives the call, so callers are free to mutate it afterwards.
+    pub fn for_each_cell(&self, raw: &[u8], mut f: impl FnMut(&[u8])) {
+        for cell in self.iter_cells(raw) {
+            f(&cell);
+        }
+    }
+
+    /// Iterate over all rows in the solution, as lists of cells
+    ///
+    /// If the column count is known, rows are made of that many cells regardless
+    /// of where the newlines are. Otherwise every valid newline ends a row, like
+    /// the easy parser, but newlines inside quote pairs still belong to their cell.
+    pub fn iter_rows<'t>(&'t self, raw: &'t [u8]) -> impl Iterator<Item = Vec<Cow<'t, [u8]>>> + 't {
+        self.iter_row_spans().map(move |row| {
+            row.iter()
+                .map(|cell| cell.contents(raw, self.cr_handling))
+                .collect()
+        })
+    }
+
+    /// The names in the header row
+    ///
+    /// The header is the first row, grouped as in `iter_rows`. Quoted names are
+    /// unescaped like any other cell, and any invalid UTF-8 is replaced.
+    pub fn header(&self, raw: &[u8]) -> Option<Vec<String>> {
+        self.iter_rows(raw).next().map(|row| {
+            row.iter()
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .collect()
+        })
+    }
+
+    /// Find the byte range of a cell in the raw input
+    ///
+    /// Rows are grouped the same way as in `iter_rows`. For a quoted cell the range
+    /// excludes the quotes, but any doubled quotes inside it are still escaped.
+    pub fn cell_span(&self, row: usize, col: usize) -> Option<Range<usize>> {
+        let row = self.iter_row_spans().nth(row)?;
+        row.into_iter().nth(col).map(|cell| cell.range)
+    }
+
+    /// The cells of one row, counting from zero
+    ///
+    /// Rows are grouped the same way as in `iter_rows`, and only the rows up to
+    /// this one are read. As in `cell_span`, quoted cells exclude their quotes but
+    /// any doubled quotes inside them are still escaped.
+    pub fn cells_in_row<'t>(&self, raw: &'t [u8], row: usize) -> Option<Vec<&'t [u8]>> {
+        let row = self.iter_row_spans().nth(row)?;
+        Some(row.into_iter().map(|cell| &raw[cell.range]).collect())
+    }
+
+    /// Whether a cell was quoted in the raw input
+    ///
+    /// Rows are grouped the same way as in `iter_rows`, and a cell that doesn't
+    /// exist wasn't quoted. This lets a repair keep the source's own quoting
+    /// instead of imposing a new style.
+    pub fn was_quoted(&self, row: usize, col: usize) -> bool {
+        self.iter_row_spans()
+            .nth(row)
+            .and_then(|row| row.into_iter().nth(col))
+            .is_some_and(|cell| cell.quoted)
+    }
+
+    /// List the rows where the solution departs from a naive split
+    ///
+    /// A naive parser ends a cell at every delimiter and a row at every newline.
+    /// A row counts as repaired if an unquoted cell swallowed a delimiter or
+    /// newline, if a newline was treated as a cell boundary inside the row, or if
+    /// the row ended somewhere other than a newline. Delimiters and newlines inside
+    /// quoted cells are valid CSV, so they don't count.
+    pub fn repaired_rows(&self) -> Vec<usize> {
+        self.iter_row_spans()
+            .enumerate()
+            .filter(|(_, row)| {
+                let last = row.len() - 1;
+                row.iter().enumerate().any(|(col, cell)| {
+                    let swallowed_delimiter = !cell.quoted && {
+                        let first = self
+                            .delimiter_locations
+                            .partition_point(|&location| location < cell.range.start);
+                        self.delimiter_locations
+                            .get(first)
+                            .is_some_and(|&location| location < cell.range.end)
+                    };
+                    swallowed_delimiter || cell.ends_line != (col == last)
+                })
+            })
+            .map(|(row, _)| row)
+            .collect()
+    }
+
+    /// List the changes that would turn the input into valid CSV, without making them
+    ///
+    /// Each action is at a byte offset in `raw`, and they are in order. This is the
+    /// detail behind [`Solution::repaired_rows`], plus quotes that need escaping,
+    /// which that leaves out because they don't change how the rows split.
+    pub fn repair_plan(&self, raw: &[u8]) -> Vec<RepairAction> {
+        let mut plan = Vec::new();
+        for row in self.iter_row_spans() {
+            let last = row.len() - 1;
+            for (col, cell) in row.iter().enumerate() {
+                let mut bytes = cell.range.clone().zip(&raw[cell.range.clone()]).peekable();
+                while let Some((position, &byte)) = bytes.next() {
+                    if byte == b'"' {
+                        // A doubled quote in a quoted cell is already escaped
+                        if !(cell.quoted && bytes.next_if(|&(_, &next)| next == b'"').is_some()) {
+                            plan.push(RepairAction::EscapeQuote { position });
+                        }
+                    } else if !cell.quoted
+                        && self.delimiter_locations.binary_search(&position).is_ok()
+                    {
+                        plan.push(RepairAction::MergeCells { position });
                     }
-                    if cell.ends_with(&[b'"']) {
-                        cell = &cell[..cell.len() - 1];
+                }
+                // The closing quote sits between a quoted cell and its boundary
+                let boundary = cell.range.end + usize::from(cell.quoted);
+                if boundary < self.file_length {
+                    if cell.ends_line && col != last {
+                        plan.push(RepairAction::JoinRows { position: boundary });
+                    } else if !cell.ends_line && col == last {
+                        plan.push(RepairAction::SplitRows { position: boundary });
                     }
-                    cell
                 }
+            }
+        }
+        plan.sort_by_key(RepairAction::position);
+        plan
+    }
+
+    /// Iterate over the cells in each row
+    fn iter_row_spans(&self) -> impl Iterator<Item = Vec<CellSpan>> + '_ {
+        self.iter_row_spans_from(0)
+    }
+
+    /// Iterate over the cells in each row, starting from a row boundary
+    fn iter_row_spans_from(&self, from: usize) -> impl Iterator<Item = Vec<CellSpan>> + '_ {
+        let mut cells = self.iter_cell_spans(from);
+        std::iter::from_fn(move || {
+            let mut row = Vec::new();
+            for cell in cells.by_ref() {
+                let ends_line = cell.ends_line;
+                row.push(cell);
+                match self.column_count {
+                    Some(column_count) if row.len() >= column_count => break,
+                    None if ends_line => break,
+                    _ => {}
+                }
+            }
+            (!row.is_empty()).then_some(row)
+        })
+    }
+
+    /// The range of a cell, and whether it is quoted
+    ///
+    /// A quote pair only quotes the cell if it spans all of it, from `cell_start` to
+    /// `cell_end` where the delimiter or newline is, allowing for a `\r` before a
+    /// newline. Otherwise the quotes are part of the cell's text, so that no bytes
+    /// around them are lost.
+    fn quoted_or_raw(
+        &self,
+        quoted_range: Option<Range<usize>>,
+        cell_start: usize,
+        cell_end: usize,
+    ) -> (Range<usize>, bool) {
+        match quoted_range {
+            Some(range)
+                if range.start == cell_start + 1
+                    && (range.end + 1 == cell_end
+                        || (range.end + 2 == cell_end
+                            && self
+                                .delimiter_locations
+                                .binary_search(&(range.end + 1))
+                                .is_err())) =>
+            {
+                (range, true)
+            }
+            _ => (cell_start..cell_end, false),
+        }
+    }
+
+    /// Iterate over the locations of all cells
+    ///
+    /// A newline at the very end of the file ends the last line rather than
+    /// starting an empty one.
+    ///
+    /// Cells are read from `from`, which has to be the start of a cell outside of
+    /// any quote pair.
+    fn iter_cell_spans(&self, from: usize) -> impl Iterator<Item = CellSpan> + '_ {
+        let mut specials = self.iter_specials(from);
+        let mut cell_start = from;
+        let mut at_line_start = true;
+        let mut finished = false;
+        std::iter::from_fn(move || {
+            let mut quote_start = None;
+            let mut quoted_range = None;
+            for (index, class) in specials.by_ref() {
+                match class {
+                    CharacterClass::Quote => match quote_start.take() {
+                        None => quote_start = Some(index),
+                        Some(start) => quoted_range = Some(start + 1..index),
+                    },
+                    _ => {
+                        let (range, quoted) = self.quoted_or_raw(quoted_range, cell_start, index);
+                        let cell = CellSpan {
+                            quoted,
+                            range,
+                            ends_line: class == CharacterClass::Newline,
+                            next: index + 1,
+                        };
+                        cell_start = index + 1;
+                        at_line_start = cell.ends_line;
+                        return Some(cell);
+                    }
+                }
+            }
+            // The last cell runs to the end of the file, unless the file ends with a newline
+            if finished || (at_line_start && cell_start == self.file_length) {
+                return None;
+            }
+            finished = true;
+            let (range, quoted) = self.quoted_or_raw(quoted_range, cell_start, self.file_length);
+            Some(CellSpan {
+                quoted,
+                range,
+                ends_line: true,
+                next: self.file_length,
             })
-            .chain(std::iter::once(&raw[prev_index..]))
+        })
+    }
+}
+
+/// Parse messy CSV by searching for the most consistent columns
+///
+/// Rows are made of `column_count` cells if it is given, so that stray newlines can
+/// be repaired; otherwise every valid newline ends a row. See [`Solution::solve`]
+/// for the search, which runs with the default [`SolveBudget`], and for when it
+/// gives up with `CsvError::Ambiguity`.
+pub fn parse_medium(
+    raw: &[u8],
+    delimiter: u8,
+    column_count: Option<usize>,
+) -> Result<Vec<Vec<Vec<u8>>>> {
+    let mut solution = Solution::new(raw, delimiter);
+    if let Some(column_count) = column_count {
+        solution = solution.with_column_count(column_count);
+    }
+    solution.solve(raw, SolveBudget::default())?;
+    Ok(solution
+        .iter_rows(raw)
+        .map(|row| row.into_iter().map(Cow::into_owned).collect())
+        .collect())
+}
+
+/// Find the best few interpretations of messy CSV, best first
+///
+/// The first is the solution from [`Solution::solve`], run with the default
+/// [`SolveBudget`]; if that runs out, the best solution found so far is used
+/// instead. The rest differ from it by one delimiter, newline or quote, so they
+/// are the closest alternatives. Each comes with its total gini impurity, and
+/// they are sorted by that, then by having fewer quote pairs, then by where in
+/// the file the change is, so the order is the same on every run. Solutions that
+/// split the cells the same way as one before them are left out, and there are
+/// fewer than `k` if there aren't enough changes to make.
+pub fn solve_ranked(raw: &[u8], delimiter: u8, k: usize) -> Vec<(f64, Solution)> {
+    let mut best = Solution::new(raw, delimiter);
+    // Running out of budget still leaves a solution to rank around
+    let _ = best.solve(raw, SolveBudget::default());
+    let mut alternatives = best
+        .score_flips(raw, &GiniObjective)
+        .into_iter()
+        .map(|(score, quote_pairs, flip)| (score, quote_pairs, Some(flip)))
+        .collect::<Vec<_>>();
+    alternatives.push((
+        total_score(&best.column_complexities, &GiniObjective),
+        best.iter_quote_pairs(0).count(),
+        None,
+    ));
+    alternatives.sort_by(|a, b| {
+        a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then_with(|| {
+            a.2.map(|flip| best.flip_location(flip))
+                .cmp(&b.2.map(|flip| best.flip_location(flip)))
+        })
+    });
+    let mut ranked: Vec<(f64, Solution)> = Vec::new();
+    for (score, _, flip) in alternatives {
+        if ranked.len() == k {
+            break;
+        }
+        let mut solution = best.clone();
+        if let Some(flip) = flip {
+            solution.flip(flip);
+            solution.update_complexities(raw);
+        }
+        let repeated = ranked
+            .iter()
+            .any(|(_, other)| other.iter_row_spans().eq(solution.iter_row_spans()));
+        if !repeated {
+            ranked.push((score, solution));
+        }
+    }
+    ranked
+}
+
+/// One change a solution makes to its input, from [`Solution::repair_plan`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepairAction {
+    /// The delimiter or newline here is part of a cell, so the cells either side
+    /// of it are one cell
+    MergeCells { position: usize },
+    /// The quote here is part of a cell, so it needs escaping
+    EscapeQuote { position: usize },
+    /// The newline here separates two cells of the same row
+    JoinRows { position: usize },
+    /// The delimiter here ends a row, as if it were a newline
+    SplitRows { position: usize },
+}
+impl RepairAction {
+    /// The byte offset of the character this action changes
+    pub fn position(&self) -> usize {
+        match *self {
+            RepairAction::MergeCells { position }
+            | RepairAction::EscapeQuote { position }
+            | RepairAction::JoinRows { position }
+            | RepairAction::SplitRows { position } => position,
+        }
+    }
+}
+
+/// How well each objective picked the right delimiter, from [`compare_objectives`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectiveComparison {
+    /// How many labelled samples there were
+    pub samples: usize,
+    /// How many samples each objective resolved correctly, in the order given
+    pub correct: Vec<usize>,
+}
+impl ObjectiveComparison {
+    /// The fraction of samples an objective resolved correctly
+    ///
+    /// Objectives are counted in the order they were given to
+    /// [`compare_objectives`]. With no samples there is nothing to get wrong, so
+    /// this is 1.
+    pub fn accuracy(&self, objective: usize) -> f64 {
+        let correct = self.correct.get(objective).copied().unwrap_or(0);
+        if self.samples == 0 {
+            1.0
+        } else {
+            correct as f64 / self.samples as f64
+        }
+    }
+}
+
+/// Measure which objective best tells the right delimiter from the wrong ones
+///
+/// Each sample is raw CSV labelled with its true delimiter, and the candidates are
+/// every delimiter that labels any sample. For each objective, a sample is split
+/// with each candidate and scored by the mean of its columns' scores, weighted by
+/// their length. The objective resolves the sample if the lowest score belongs to
+/// the label, with ties going to whichever candidate was labelled first.
+pub fn compare_objectives(
+    samples: &[(&[u8], u8)],
+    objectives: &[&dyn ColumnObjective],
+) -> ObjectiveComparison {
+    let mut candidates = Vec::new();
+    for &(_, label) in samples {
+        if !candidates.contains(&label) {
+            candidates.push(label);
+        }
+    }
+    let correct = objectives
+        .iter()
+        .map(|&objective| {
+            samples
+                .iter()
+                .filter(|&&(raw, label)| {
+                    let best = candidates
+                        .iter()
+                        .map(|&candidate| (candidate, split_score(raw, candidate, objective)))
+                        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                        .map(|(candidate, _)| candidate);
+                    best == Some(label)
+                })
+                .count()
+        })
+        .collect();
+    ObjectiveComparison {
+        samples: samples.len(),
+        correct,
+    }
+}
+
+/// The length-weighted mean score of the columns of `raw` split on `delimiter`
+fn split_score(raw: &[u8], delimiter: u8, objective: &dyn ColumnObjective) -> f64 {
+    let solution = Solution::new(raw, delimiter);
+    let mut columns: Vec<ColumnComplexity> = Vec::new();
+    for row in solution.iter_rows(raw) {
+        if columns.len() < row.len() {
+            columns.resize(row.len(), ColumnComplexity::default());
+        }
+        for (column, cell) in columns.iter_mut().zip(row) {
+            column.add_bytes(&cell);
+        }
+    }
+    let total = columns.iter().map(ColumnComplexity::len).sum::<usize>();
+    if total == 0 {
+        return 0.0;
+    }
+    columns
+        .iter()
+        .map(|column| objective.score(column) * column.len() as f64)
+        .sum::<f64>()
+        / total as f64
+}
+
+/// Join cells into one line with a separator, quoting cells that need it
+///
+/// A cell containing the separator, a quote or a newline is quoted, with its quotes
+/// doubled, so the line can be split back into the same cells. This is mostly for
+/// printing the output of `iter_cells` or `iter_rows` while debugging.
+pub fn join_row<'t>(cells: impl Iterator<Item = &'t [u8]>, sep: &[u8]) -> Vec<u8> {
+    let mut line = Vec::new();
+    for (index, cell) in cells.enumerate() {
+        if index > 0 {
+            line.extend_from_slice(sep);
+        }
+        let needs_quotes = (!sep.is_empty() && cell.windows(sep.len()).any(|window| window == sep))
+            || cell
+                .iter()
+                .any(|&byte| byte == b'"' || byte == b'\n' || byte == b'\r');
+        if needs_quotes {
+            line.push(b'"');
+            for &byte in cell {
+                if byte == b'"' {
+                    line.push(b'"');
+                }
+                line.push(byte);
+            }
+            line.push(b'"');
+        } else {
+            line.extend_from_slice(cell);
+        }
+    }
+    line
+}
+
+/// The line and column of a byte in the raw input
+fn position_of(raw: &[u8], index: usize) -> Position {
+    let line_start = raw[..index]
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+    Position {
+        line: raw[..line_start]
+            .iter()
+            .filter(|&&byte| byte == b'\n')
+            .count(),
+        column: index - line_start,
+    }
+}
+
+/// The total score of some columns, which the solver minimises
+fn total_score(columns: &[ColumnComplexity], objective: &dyn ColumnObjective) -> f64 {
+    columns.iter().map(|column| objective.score(column)).sum()
+}
+
+/// Where the row after this one starts
+fn row_end(row: &[CellSpan]) -> usize {
+    row[row.len() - 1].next
+}
+
+/// Add the cells of a row to the columns they fall in
+fn add_row(
+    columns: &mut Vec<ColumnComplexity>,
+    row: &[CellSpan],
+    raw: &[u8],
+    cr_handling: CrHandling,
+) {
+    if columns.len() < row.len() {
+        columns.resize(row.len(), ColumnComplexity::default());
+    }
+    for (column, cell) in columns.iter_mut().zip(row) {
+        column.add_bytes_with(&cell.contents(raw, cr_handling), cr_handling);
+    }
+}
+
+/// Add some bytes measured elsewhere to one column
+fn add_column(columns: &mut Vec<ColumnComplexity>, column: usize, bytes: &ColumnComplexity) {
+    if columns.len() <= column {
+        columns.resize(column + 1, ColumnComplexity::default());
+    }
+    columns[column].merge(bytes);
+}
+
+/// Where one change to a solution can start to make a difference
+struct Stretch<'t> {
+    /// The columns of the rows before `row_start`
+    prefix: &'t [ColumnComplexity],
+    /// The start of the row the change is in
+    row_start: usize,
+    /// The byte offset of the change
+    location: usize,
+}
+
+/// One delimiter or quote the solver can change, by its index in its list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flip {
+    Delimiter(usize),
+    Quote(usize),
+}
+
+/// The location of one cell in the raw input
+#[derive(Debug, Clone, PartialEq)]
+struct CellSpan {
+    /// The bytes of the cell, excluding any surrounding quotes
+    range: Range<usize>,
+    /// Whether the cell is surrounded by a quote pair
+    quoted: bool,
+    /// Whether the cell is the last one on its line
+    ends_line: bool,
+    /// Where the next cell starts, just past the delimiter or newline ending this one
+    next: usize,
+}
+impl CellSpan {
+    /// The contents of the cell, with doubled quotes unescaped if it is quoted
+    ///
+    /// With [`CrHandling::Space`], carriage returns are replaced by spaces too.
+    fn contents<'t>(&self, raw: &'t [u8], cr_handling: CrHandling) -> Cow<'t, [u8]> {
+        let cell = &raw[self.range.clone()];
+        let unescape = self.quoted && cell.windows(2).any(|pair| pair == b"\"\"");
+        let replace = cr_handling == CrHandling::Space && cell.contains(&b'\r');
+        if !unescape && !replace {
+            return Cow::Borrowed(cell);
+        }
+        let mut contents = Vec::with_capacity(cell.len());
+        let mut bytes = cell.iter().peekable();
+        while let Some(&byte) = bytes.next() {
+            contents.push(if replace && byte == b'\r' { b' ' } else { byte });
+            if unescape && byte == b'"' && bytes.peek() == Some(&&b'"') {
+                bytes.next();
+            }
+        }
+        Cow::Owned(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_heuristics_adjacency() {
+        let raw = b"\"a\",\"b,c\"\n\"d\"\"e\",f\"\n,\"\",\"x\"y\"";
+        let solution = Solution::new(raw, b',');
+        // Compare against checking every quote's neighbours directly
+        let is_delimiter = |i: usize| raw[i] == b',' || raw[i] == b'\n';
+        for (quote_num, &quote_byte) in solution.quote_locations.iter().enumerate() {
+            let prev = quote_byte == 0 || is_delimiter(quote_byte - 1);
+            let next = quote_byte == raw.len() - 1 || is_delimiter(quote_byte + 1);
+            assert!(
+                prev || !solution.quote_can_start[quote_num],
+                "quote at {quote_byte}"
+            );
+            assert!(
+                next || !solution.quote_can_end[quote_num],
+                "quote at {quote_byte}"
+            );
+        }
+        assert_eq!(
+            solution.iter_cells(raw).collect::<Vec<_>>(),
+            vec![&b"a"[..], b"b,c", b"d\"e", b"f\"", b"", b"", b"x\"y"]
+        );
+    }
+
+    /// Time the initial parse of a quote-heavy file
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_new_quote_heavy() {
+        let raw = "\"abc\",\"d,e\",\"\",\"f\"\"g\"\n".repeat(200_000);
+        let start = std::time::Instant::now();
+        let solution = Solution::new(raw.as_bytes(), b',');
+        println!(
+            "Solution::new on {} bytes with {} quotes took {:?}",
+            raw.len(),
+            solution.quote_locations.len(),
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_mixed_quoting_within_column() {
+        let raw = b"1,\"a,b\",2\n3,c,4\n5,\"d\",6\n";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.iter_rows(raw).collect::<Vec<_>>(),
+            vec![
+                vec![&b"1"[..], b"a,b", b"2"],
+                vec![b"3", b"c", b"4"],
+                vec![b"5", b"d", b"6"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_has_quotes() {
+        let raw = b"a,b\"c,d\n1,2,3\n";
+        let solution = Solution::new(raw, b',');
+        assert!(!solution.has_quotes());
+        assert_eq!(
+            solution.iter_cells(raw).collect::<Vec<_>>(),
+            vec![&b"a"[..], b"b\"c", b"d", b"1", b"2", b"3"]
+        );
+        let raw = b"1,ada\n2,bob\n3,cy\n";
+        let mut solution = Solution::new(raw, b',');
+        solution.solve(raw, SolveBudget::default()).unwrap();
+        // One round over the three delimiters and three newlines, and no quotes
+        assert_eq!(solution.stats().iterations, 6);
+        assert_eq!(solution.stats().quote_pairs_considered, 0);
+        assert_eq!(
+            solution.iter_rows(raw).collect::<Vec<_>>(),
+            vec![
+                vec![&b"1"[..], b"ada"],
+                vec![b"2", b"bob"],
+                vec![b"3", b"cy"]
+            ]
+        );
+        let raw = b"a,\"b,c\"\n";
+        assert!(Solution::new(raw, b',').has_quotes());
+    }
+
+    #[test]
+    fn test_cell_span() {
+        let raw = b"a,b,c\n1,22,333\n4,5,6\n";
+        let solution = Solution::new(raw, b',');
+        let span = solution.cell_span(1, 2).unwrap();
+        assert_eq!(span, 11..14);
+        assert_eq!(&raw[span], b"333");
+        assert_eq!(&raw[solution.cell_span(2, 0).unwrap()], b"4");
+        assert_eq!(solution.cell_span(1, 3), None);
+        assert_eq!(solution.cell_span(3, 0), None);
+    }
+
+    #[test]
+    fn test_cells_in_row() {
+        let raw = b"1,plain\n2,split\nline\n3,\"a,b\"\n4,more\n";
+        let mut solution = Solution::new(raw, b',').with_column_count(2);
+        solution.solve(raw, SolveBudget::default()).unwrap();
+        assert_eq!(
+            solution.cells_in_row(raw, 1),
+            Some(vec![&b"2"[..], b"split\nline"])
+        );
+        assert_eq!(solution.cells_in_row(raw, 2), Some(vec![&b"3"[..], b"a,b"]));
+        assert_eq!(solution.cells_in_row(raw, 4), None);
+    }
+
+    #[test]
+    fn test_solve_ranked() {
+        let raw = b"1,a\n2,b\n3,c,d\n";
+        let ranked = solve_ranked(raw, b',', 3);
+        assert_eq!(ranked.len(), 3);
+        assert!(ranked.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+        let mut solved = Solution::new(raw, b',');
+        solved.solve(raw, SolveBudget::default()).unwrap();
+        assert_eq!(ranked[0].1, solved);
+        for (score, solution) in &ranked {
+            let rows = solution.iter_rows(raw).collect::<Vec<_>>();
+            assert_eq!(
+                *score,
+                total_score(&solution.column_complexities, &GiniObjective)
+            );
+            assert_eq!(
+                ranked
+                    .iter()
+                    .filter(|(_, other)| other.iter_rows(raw).collect::<Vec<_>>() == rows)
+                    .count(),
+                1
+            );
+        }
+        assert_eq!(solve_ranked(raw, b',', 3), ranked);
+        assert!(solve_ranked(raw, b',', 0).is_empty());
+    }
+
+    #[test]
+    fn test_total_impurity() {
+        let raw = b"a1,b\nc2,d\n";
+        let mut solution = Solution::new(raw, b',');
+        assert_eq!(solution.total_impurity(), 0.0);
+        assert_eq!(solution.mean_impurity(), 0.0);
+        solution.solve(raw, SolveBudget::default()).unwrap();
+        // Half letters and half digits in four bytes, then two letters
+        assert_eq!(solution.total_impurity(), 0.5 * 4.0);
+        assert_eq!(solution.mean_impurity(), 0.5 * 4.0 / 6.0);
+    }
+
+    #[test]
+    fn test_solve_with() {
+        let raw = b"1,hello\nworld\n2,hi\n3,ok\n";
+        let initial = Solution::new(raw, b',').with_column_count(2);
+        let mut gini = initial.clone();
+        gini.solve(raw, SolveBudget::default()).unwrap();
+        let mut entropy = initial.clone();
+        entropy
+            .solve_with(raw, SolveBudget::default(), &EntropyObjective)
+            .unwrap();
+        assert_eq!(entropy.iter_rows(raw).count(), 3);
+        let mut presence = initial.clone();
+        presence
+            .solve_with(raw, SolveBudget::default(), &PresenceObjective)
+            .unwrap();
+        assert_eq!(presence.iter_rows(raw).count(), 3);
+
+        // Nothing beats a score of zero, so this keeps the heuristics as they are
+        struct Indifferent;
+        impl ColumnObjective for Indifferent {
+            fn score(&self, _column: &ColumnComplexity) -> f64 {
+                0.0
+            }
+        }
+        let mut indifferent = initial.clone();
+        indifferent
+            .solve_with(raw, SolveBudget::default(), &Indifferent)
+            .unwrap();
+        assert_eq!(indifferent.iter_rows(raw).count(), 4);
+        assert_eq!(gini.iter_rows(raw).count(), 3);
+    }
+
+    #[test]
+    fn test_quote_pair_inside_cell() {
+        // Without the comma, the quotes no longer start the cell, so they are text
+        let raw = b"a,\"b\"\nc";
+        let mut solution = Solution::new(raw, b',');
+        solution.flip(Flip::Delimiter(0));
+        assert_eq!(
+            solution.iter_cells(raw).collect::<Vec<_>>(),
+            vec![&b"a,\"b\""[..], b"c"]
+        );
+        assert!(!solution.was_quoted(0, 0));
+    }
+
+    #[test]
+    fn test_iter_rows_without_column_count() {
+        let raw = b"a,\"b\nc\",d\ne,f\n\"g\",h,\"i\n\"\n";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.iter_rows(raw).collect::<Vec<_>>(),
+            vec![
+                vec![&b"a"[..], b"b\nc", b"d"],
+                vec![b"e", b"f"],
+                vec![b"g", b"h", b"i\n"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iter_rows_with_column_count() {
+        let raw = b"a,b\nc\nd,e,f\n";
+        let solution = Solution::new(raw, b',').with_column_count(3);
+        assert_eq!(
+            solution.iter_rows(raw).collect::<Vec<_>>(),
+            vec![vec![&b"a"[..], b"b", b"c"], vec![b"d", b"e", b"f"]]
+        );
+    }
+
+    #[test]
+    fn test_iter_cells_empty_quoted_cell() {
+        let raw = b"a,\"\",b";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.iter_cells(raw).collect::<Vec<_>>(),
+            vec![&b"a"[..], b"", b"b"]
+        );
+    }
+
+    #[test]
+    fn test_iter_cells_escaped_quote() {
+        let raw = b"\"\"\"\"";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.iter_cells(raw).collect::<Vec<_>>(),
+            vec![&b"\""[..]]
+        );
+        let raw = b"x,\"say \"\"hi\"\"\",y";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.iter_cells(raw).collect::<Vec<_>>(),
+            vec![&b"x"[..], b"say \"hi\"", b"y"]
+        );
+    }
+
+    #[test]
+    fn test_repaired_rows() {
+        let raw = b"a,b,c\n1,2\n3\n4,\"5,\n5\",6\n";
+        let solution = Solution::new(raw, b',').with_column_count(3);
+        assert_eq!(
+            solution.iter_rows(raw).collect::<Vec<_>>(),
+            vec![
+                vec![&b"a"[..], b"b", b"c"],
+                vec![b"1", b"2", b"3"],
+                vec![b"4", b"5,\n5", b"6"],
+            ]
+        );
+        assert_eq!(solution.repaired_rows(), vec![1]);
+
+        let raw = b"a,b,c\nd,e,f\n";
+        let solution = Solution::new(raw, b',');
+        assert!(solution.repaired_rows().is_empty());
+    }
+
+    #[test]
+    fn test_for_each_cell() {
+        let raw = b"a,b,c\n1,22,333";
+        let solution = Solution::new(raw, b',');
+        let mut cells = Vec::new();
+        solution.for_each_cell(raw, |cell| cells.push(cell.to_vec()));
+        let expected = solution
+            .iter_cells(raw)
+            .map(|cell| cell.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(cells, expected);
+    }
+
+    #[test]
+    fn test_with_detection() {
+        let raw = b"a;b;c\n1;2,5;3\n";
+        let solution = Solution::with_detection(raw, b",;\t").unwrap();
+        assert_eq!(solution.delimiter(), b';');
+        assert_eq!(&raw[solution.cell_span(0, 2).unwrap()], b"c");
+        assert_eq!(&raw[solution.cell_span(1, 1).unwrap()], b"2,5");
+        assert_eq!(solution.cell_span(1, 3), None);
+        assert!(Solution::with_detection(b"abc\n", b",;").is_err());
+    }
+
+    #[test]
+    fn test_quoted_header() {
+        let raw = b"\"first,name\",\"age\"\nAda,36\n";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.header(raw),
+            Some(vec!["first,name".to_string(), "age".to_string()])
+        );
+        let raw = b"\"say \"\"hi\"\"\",b\n";
+        assert_eq!(
+            Solution::new(raw, b',').header(raw),
+            Some(vec!["say \"hi\"".to_string(), "b".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_with_strict_mode() {
+        let raw = b"a,b,c\nx,\",y,\",z\n";
+        let lenient = Solution::with_strict_mode(raw, b',', false).unwrap();
+        assert_eq!(lenient.iter_rows(raw).count(), 2);
+        assert_eq!(
+            Solution::with_strict_mode(raw, b',', true).unwrap_err(),
+            CsvError::Ambiguity(
+                Position { line: 1, column: 2 },
+                "Quote could either start or end a cell"
+            )
+        );
+        let raw = b"a,b\n\"x,y\",z\n";
+        assert!(Solution::with_strict_mode(raw, b',', true).is_ok());
+    }
+
+    #[test]
+    fn test_join_row() {
+        let raw = b"a,\"b|c\",\"say \"\"hi\"\"\"\n";
+        let solution = Solution::new(raw, b',');
+        let row = solution.iter_rows(raw).next().unwrap();
+        assert_eq!(
+            join_row(row.iter().map(|cell| cell.as_ref()), b"|"),
+            b"a|\"b|c\"|\"say \"\"hi\"\"\"".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_pin_newline() {
+        let raw = b"1,first\nline\n2,x\n";
+        let rows = |solution: &Solution| -> Vec<Vec<Vec<u8>>> {
+            solution
+                .iter_rows(raw)
+                .map(|row| row.iter().map(|cell| cell.to_vec()).collect())
+                .collect()
+        };
+        let mut solution = Solution::new(raw, b',');
+        assert_eq!(rows(&solution).len(), 3);
+
+        solution.unpin_newline(7).unwrap();
+        assert_eq!(
+            rows(&solution),
+            vec![
+                vec![b"1".to_vec(), b"first\nline".to_vec()],
+                vec![b"2".to_vec(), b"x".to_vec()],
+            ]
+        );
+
+        solution.pin_newline(7).unwrap();
+        assert_eq!(rows(&solution).len(), 3);
+        assert_eq!(
+            solution.pin_newline(15),
+            Err(CsvError::invalid_at(
+                2,
+                2,
+                "There is no newline at this offset"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_pin_newline_survives_solve() {
+        let raw = b"1,ada\n2,bob\n3,cy\n";
+        let mut solution = Solution::new(raw, b',');
+        solution.unpin_newline(5).unwrap();
+        solution.solve(raw, SolveBudget::default()).unwrap();
+        // The newline stays inside a cell, whatever else the solver changes
+        assert_eq!(
+            solution.iter_rows(raw).collect::<Vec<_>>(),
+            vec![vec![&b"1"[..], b"ada\n2,bob"], vec![b"3", b"cy"]]
+        );
+        // Without the pin, the solver puts the newline back
+        let mut solution = Solution::new(raw, b',');
+        solution.unpin_newline(5).unwrap();
+        solution.pinned.set(1, false);
+        solution.solve(raw, SolveBudget::default()).unwrap();
+        assert_eq!(solution.iter_rows(raw).count(), 3);
+    }
+
+    #[test]
+    fn test_protected_delimiter_count() {
+        let raw = b"name,address\n\"Lee, B\",\"1 Main St, Springfield\nUSA\"\nAda,London\n";
+        assert_eq!(Solution::new(raw, b',').protected_delimiter_count(), 2);
+        assert_eq!(Solution::new(b"a,b\n", b',').protected_delimiter_count(), 0);
+    }
+
+    #[test]
+    fn test_was_quoted() {
+        let raw = b"id,name\n1,\"Lee, B\"\n\"2\",Ada\n";
+        let solution = Solution::new(raw, b',');
+        assert!(solution.was_quoted(1, 1));
+        assert!(solution.was_quoted(2, 0));
+        assert!(!solution.was_quoted(1, 0));
+        assert!(!solution.was_quoted(2, 1));
+        assert!(!solution.was_quoted(5, 0));
+    }
+
+    #[test]
+    fn test_quote_at_start_of_file() {
+        let raw = b"\"a,b\",c";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.iter_cells(raw).collect::<Vec<_>>(),
+            vec![&b"a,b"[..], b"c"]
+        );
+        assert!(solution.was_quoted(0, 0));
+        assert!(!solution.was_quoted(0, 1));
+    }
+
+    #[test]
+    fn test_iter_specials() {
+        let raw = b"a,\"b,c\"\nd";
+        let solution = Solution::new(raw, b',');
+        assert_eq!(
+            solution.iter_specials(0).collect::<Vec<_>>(),
+            vec![
+                (1, CharacterClass::Comma),
+                (2, CharacterClass::Quote),
+                (6, CharacterClass::Quote),
+                (7, CharacterClass::Newline),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_medium() {
+        let raw = b"1,hello\nworld\n2,hi\n3,ok\n";
+        assert_eq!(
+            parse_medium(raw, b',', Some(2)).unwrap(),
+            vec![
+                vec![b"1".to_vec(), b"hello\nworld".to_vec()],
+                vec![b"2".to_vec(), b"hi".to_vec()],
+                vec![b"3".to_vec(), b"ok".to_vec()],
+            ]
+        );
+        let raw = b"1,\"x,y\"\n2,z\n";
+        assert_eq!(
+            parse_medium(raw, b',', None).unwrap(),
+            vec![
+                vec![b"1".to_vec(), b"x,y".to_vec()],
+                vec![b"2".to_vec(), b"z".to_vec()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_solve_stats() {
+        let mut trivial = Solution::new(b"a,b\n1,2\n", b',');
+        assert_eq!(trivial.stats(), SolveStats::default());
+        trivial
+            .solve(b"a,b\n1,2\n", SolveBudget::default())
+            .unwrap();
+        let raw =
+            b"id,name,note\n1,\"Smith, J\",\"a \"\"b\"\"\"\n2,Ada,\"x\ny\"\n3,Bob,\"5\" tall\"\n";
+        let mut hard = Solution::new(raw, b',');
+        hard.solve(raw, SolveBudget::default()).unwrap();
+        let (trivial, hard_stats) = (trivial.stats(), hard.stats());
+        assert!(trivial.iterations < hard_stats.iterations);
+        assert_eq!(trivial.quote_pairs_considered, 0);
+        assert!(hard_stats.quote_pairs_considered > 0);
+        assert!(!trivial.budget_hit && !hard_stats.budget_hit);
+
+        let mut limited = Solution::new(raw, b',');
+        let budget = SolveBudget {
+            max_steps: 3,
+            max_duration: None,
+        };
+        assert!(limited.solve(raw, budget).is_err());
+        assert_eq!(limited.stats().iterations, 3);
+        assert!(limited.stats().budget_hit);
+    }
+
+    #[test]
+    fn test_confidence_threshold() {
+        let threshold = ConfidenceThreshold {
+            min_margin: 0.05,
+            max_mean_impurity: 0.5,
+        };
+        let raw = b"1,ada\n2,bob\n3,cy\n";
+        let mut clear = Solution::new(raw, b',').with_confidence_threshold(threshold);
+        clear.solve(raw, SolveBudget::default()).unwrap();
+        assert_eq!(clear.warnings(), &[]);
+
+        // Splitting the quoted cell at its comma or not scores the same
+        let raw = b"a,\"b,c\"\n1,2\n";
+        let mut borderline = Solution::new(raw, b',').with_confidence_threshold(threshold);
+        borderline.solve(raw, SolveBudget::default()).unwrap();
+        assert_eq!(
+            borderline.warnings(),
+            &[
+                Warning {
+                    position: Position { line: 0, column: 4 },
+                    message: "Another interpretation scores almost as well",
+                },
+                Warning {
+                    position: Position { line: 0, column: 0 },
+                    message: "Columns are too mixed to be sure of the solution",
+                },
+            ]
+        );
+        assert_eq!(borderline.iter_rows(raw).count(), 2);
+        let mut unchecked = Solution::new(raw, b',');
+        unchecked.solve(raw, SolveBudget::default()).unwrap();
+        assert_eq!(unchecked.warnings(), &[]);
+    }
+
+    #[test]
+    fn test_solve_budget() {
+        let raw = b"1,hello\nworld\n2,hi\n3,ok\n";
+        let initial = Solution::new(raw, b',').with_column_count(2);
+        let mut solution = initial.clone();
+        let out_of_time = SolveBudget {
+            max_duration: Some(Duration::ZERO),
+            ..SolveBudget::default()
+        };
+        assert_eq!(
+            solution.solve(raw, out_of_time),
+            Err(CsvError::ambiguous_at(
+                0,
+                1,
+                "Solver ran out of budget before finding the best solution"
+            ))
+        );
+        assert_eq!(solution.iter_rows(raw).count(), 4);
+
+        // Seven delimiters and newlines make one round, which finds the repair
+        let mut solution = initial.clone();
+        let one_round = SolveBudget {
+            max_steps: 7,
+            max_duration: None,
+        };
+        assert!(solution.solve(raw, one_round).is_err());
+        assert_eq!(
+            solution.iter_rows(raw).collect::<Vec<_>>(),
+            vec![
+                vec![&b"1"[..], b"hello\nworld"],
+                vec![b"2", b"hi"],
+                vec![b"3", b"ok"]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_solve_long_file() {
+        // Measuring every change against the whole file took seconds even in
+        // release builds, so this ran out of time
+        let mut raw = Vec::new();
+        for i in 0..2000 {
+            let row = if i % 700 == 350 {
+                format!("{i},ab\ncd,{}\n", i * 7)
+            } else {
+                format!("{i},name{i},{}\n", i * 7)
+            };
+            raw.extend_from_slice(row.as_bytes());
+        }
+        let mut solution = Solution::new(&raw, b',');
+        let budget = SolveBudget {
+            max_duration: Some(Duration::from_secs(5)),
+            ..SolveBudget::default()
+        };
+        assert_eq!(solution.solve(&raw, budget), Ok(()));
+        let rows = solution.iter_rows(&raw).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2000);
+        assert_eq!(rows[1050], vec![&b"1050"[..], b"ab\ncd", b"7350"]);
+    }
+
+    /// A small, seeded xorshift generator, so failures are reproducible
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_measure_change_matches_measuring_everything() {
+        let alphabet = b"ab1 ,\"\n";
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        for case in 0..2000 {
+            let length = (next_random(&mut state) % 48) as usize;
+            let raw = (0..length)
+                .map(|_| alphabet[(next_random(&mut state) % alphabet.len() as u64) as usize])
+                .collect::<Vec<_>>();
+            let mut current = Solution::new(&raw, b',');
+            if case % 2 == 0 {
+                current = current.with_column_count(2);
+            }
+            // Start from a mix of valid and invalid, as the solver would
+            for delimiter_num in 0..current.delimiter_locations.len() {
+                if next_random(&mut state).is_multiple_of(4) {
+                    current.flip(Flip::Delimiter(delimiter_num));
+                }
+            }
+            for quote_num in 0..current.quote_locations.len() {
+                if next_random(&mut state).is_multiple_of(4) {
+                    current.flip(Flip::Quote(quote_num));
+                }
+            }
+            current.update_complexities(&raw);
+            let unpaired = current.unpaired_quote().unwrap_or(usize::MAX);
+            let flips = (0..current.delimiter_locations.len())
+                .map(Flip::Delimiter)
+                .chain((0..current.quote_locations.len()).map(Flip::Quote));
+            for flip in flips {
+                let location = current.flip_location(flip);
+                let mut prefix = Vec::new();
+                let mut row_start = 0;
+                for row in current.iter_row_spans() {
+                    if row_end(&row) > location.min(unpaired) {
+                        break;
+                    }
+                    row_start = row_end(&row);
+                    add_row(&mut prefix, &row, &raw, CrHandling::Newline);
+                }
+                let mut candidate = current.clone();
+                candidate.flip(flip);
+                let stretch = Stretch {
+                    prefix: &prefix,
+                    row_start,
+                    location,
+                };
+                let score = candidate.measure_change(
+                    &current,
+                    &raw,
+                    stretch,
+                    &GiniObjective,
+                    f64::INFINITY,
+                );
+                candidate.update_complexities(&raw);
+                let expected = total_score(&candidate.column_complexities, &GiniObjective);
+                assert!(
+                    score.is_some_and(|score| (score - expected).abs() < 1e-9),
+                    "case {case}, {flip:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_cr_handling() {
+        let raw = b"a,b\rc\n1,2\n";
+        let measure = |cr_handling| {
+            let mut solution = Solution::new(raw, b',').with_cr_handling(cr_handling);
+            solution.update_complexities(raw);
+            let cells = solution
+                .iter_cells(raw)
+                .map(Cow::into_owned)
+                .collect::<Vec<_>>();
+            (cells, solution.column_complexities[1].clone())
+        };
+        let as_space = ColumnComplexity::from_byte_slice_iter([&b"b c"[..], b"2"].into_iter());
+        let (cells, column) = measure(CrHandling::Newline);
+        assert_eq!(cells[1], b"b\rc");
+        assert_ne!(column, as_space);
+        let (cells, column) = measure(CrHandling::Space);
+        assert_eq!(cells[1], b"b c");
+        assert_eq!(column, as_space);
+        let (cells, column) = measure(CrHandling::Keep);
+        assert_eq!(cells[1], b"b\rc");
+        assert_eq!(column, as_space);
+    }
+
+    #[test]
+    fn test_from_byte_with() {
+        assert_eq!(
+            CharacterClass::from_byte_with(b'\r', CrHandling::Newline),
+            CharacterClass::Newline
+        );
+        assert_eq!(
+            CharacterClass::from_byte_with(b'\r', CrHandling::Space),
+            CharacterClass::Whitespace
+        );
+        assert_eq!(
+            CharacterClass::from_byte_with(b'a', CrHandling::Keep),
+            CharacterClass::Letter
+        );
+    }
+
+    #[test]
+    fn test_compare_objectives() {
+        let samples: [(&[u8], u8); 2] = [
+            (b"id,name\n1,Ada\n2,Bob\n3,Cy\n", b','),
+            (b"id;name;note\n1;Ada;x, y\n2;Bob;z\n", b';'),
+        ];
+        let objectives: [&dyn ColumnObjective; 3] =
+            [&GiniObjective, &EntropyObjective, &PresenceObjective];
+        let comparison = compare_objectives(&samples, &objectives);
+        assert_eq!(comparison.samples, 2);
+        assert_eq!(comparison.correct.len(), objectives.len());
+        // Every objective picks the right delimiter for both samples
+        assert_eq!(comparison.correct, vec![2, 2, 2]);
+        assert_eq!(comparison.accuracy(0), 1.0);
+        assert_eq!(comparison.accuracy(1), 1.0);
+        assert_eq!(comparison.accuracy(2), 1.0);
+        assert_eq!(compare_objectives(&[], &objectives).accuracy(1), 1.0);
+    }
+
+    #[test]
+    fn test_repair_plan() {
+        let raw = b"id,height,name\n1,\"5\" tall\",Ada\n2,6,Bob\n";
+        let solution = Solution::new(raw, b',');
+        let stray = raw.iter().position(|&byte| byte == b'"').unwrap() + 2;
+        assert_eq!(
+            solution.repair_plan(raw),
+            vec![RepairAction::EscapeQuote { position: stray }]
+        );
+        assert_eq!(solution.repaired_rows(), Vec::<usize>::new());
+
+        let raw = b"a,b\n\"c\"\"d\",e\n";
+        assert_eq!(Solution::new(raw, b',').repair_plan(raw), vec![]);
     }
 }
@@ -7,5 +7,18 @@
 /// This is because the simplest algorithms are deterministic and easy to reason about.
 /// The more complex parsers can handle ambiguous cases, but can actually parse valid
 /// CSV files incorrectly.
-mod easy;
-mod medium;
+pub mod aggregate;
+pub mod checksum;
+pub mod combine;
+pub mod convert;
+pub mod detect;
+pub mod dialect;
+pub mod easy;
+pub mod fixed_width;
+pub mod lookahead;
+pub mod medium;
+pub mod pipeline;
+pub mod record;
+pub mod shape;
+pub mod validate;
+pub mod writer;
@@ -0,0 +1,77 @@
+//! Transform the fields of each column as rows are parsed
+//!
+//! Cleaning usually happens column by column: trim this one, lowercase that one.
+//! A pipeline holds the steps for each column and applies them in order to every
+//! row that passes through it.
+
+use crate::errors::Result;
+use std::collections::HashMap;
+
+/// One step in a column's pipeline
+type Transform = Box<dyn Fn(String) -> String>;
+
+/// An ordered list of transforms for each column
+#[derive(Default)]
+pub struct ColumnPipeline {
+    steps: HashMap<usize, Vec<Transform>>,
+}
+impl ColumnPipeline {
+    /// Create a pipeline that leaves every column unchanged
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a transform to the end of a column's pipeline
+    pub fn then(mut self, column: usize, transform: impl Fn(String) -> String + 'static) -> Self {
+        self.steps
+            .entry(column)
+            .or_default()
+            .push(Box::new(transform));
+        self
+    }
+
+    /// Run each column's transforms over one row, in the order they were added
+    ///
+    /// Columns the row is too short to have are skipped.
+    pub fn apply(&self, row: &mut [String]) {
+        for (&column, transforms) in &self.steps {
+            if let Some(field) = row.get_mut(column) {
+                for transform in transforms {
+                    *field = transform(std::mem::take(field));
+                }
+            }
+        }
+    }
+
+    /// Apply the pipeline to every row as it is parsed
+    pub fn apply_rows<'p, I: Iterator<Item = Result<Vec<String>>> + 'p>(
+        &'p self,
+        rows: I,
+    ) -> impl Iterator<Item = Result<Vec<String>>> + 'p {
+        rows.map(move |row_result| {
+            let mut row = row_result?;
+            self.apply(&mut row);
+            Ok(row)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::easy::fast_stream_valid_csv;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_column_pipeline() {
+        let pipeline = ColumnPipeline::new()
+            .then(1, |field| field.trim().to_string())
+            .then(1, |field| field.to_uppercase());
+        let input = Cursor::new("1, ada \n2,  grace\n3\n");
+        let rows = pipeline
+            .apply_rows(fast_stream_valid_csv(input, ',', '"'))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(rows, vec![vec!["1", "ADA"], vec!["2", "GRACE"], vec!["3"]]);
+    }
+}
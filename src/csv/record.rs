@@ -0,0 +1,130 @@
+//! Rows that know their column names
+//!
+//! Parsers in this crate return each row as a `Vec<String>`, which is fine until
+//! there are enough columns that remembering their positions gets error prone.
+//! These wrap a row together with the header it was read under.
+
+use crate::csv::easy::fast_stream_valid_csv;
+use crate::errors::Result;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::Arc;
+
+/// The column names of a file, with a lookup from name to column
+#[derive(Debug, Clone, PartialEq)]
+pub struct Headers {
+    names: Vec<String>,
+    columns: HashMap<String, usize>,
+}
+impl Headers {
+    /// Index a header row
+    ///
+    /// If a name is repeated, looking it up finds its first column.
+    pub fn new(names: Vec<String>) -> Self {
+        let mut columns = HashMap::new();
+        for (column, name) in names.iter().enumerate() {
+            columns.entry(name.clone()).or_insert(column);
+        }
+        Self { names, columns }
+    }
+
+    /// The column with this name, if there is one
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.columns.get(name).copied()
+    }
+
+    /// The names, in column order
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+/// One row of fields, with the header it was read under if there was one
+#[derive(Debug, Clone, PartialEq)]
+pub struct StringRecord {
+    fields: Vec<String>,
+    headers: Option<Arc<Headers>>,
+}
+impl StringRecord {
+    /// Wrap a row, sharing a header with the other rows of its file
+    pub fn new(fields: Vec<String>, headers: Option<Arc<Headers>>) -> Self {
+        Self { fields, headers }
+    }
+
+    /// The field in the column with this name
+    ///
+    /// Returns `None` if there is no header, no column with this name, or the row
+    /// is too short to reach it.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        let column = self.headers.as_ref()?.index_of(name)?;
+        self.get_index(column)
+    }
+
+    /// The field in a column, counting from zero
+    pub fn get_index(&self, column: usize) -> Option<&str> {
+        self.fields.get(column).map(String::as_str)
+    }
+
+    /// The header this row was read under
+    pub fn headers(&self) -> Option<&Headers> {
+        self.headers.as_deref()
+    }
+
+    /// All the fields, in column order
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Unwrap the row back into its fields
+    pub fn into_fields(self) -> Vec<String> {
+        self.fields
+    }
+}
+
+/// Read valid CSV one record at a time, with fields available by column name.
+///
+/// The first row is taken as the header and shared by every record after it,
+/// rather than being returned itself. Rows are read as in [`fast_stream_valid_csv`].
+pub fn stream_records<R: BufRead>(
+    reader: R,
+    delimiter: char,
+    quote: char,
+) -> impl Iterator<Item = Result<StringRecord>> {
+    let mut rows = fast_stream_valid_csv(reader, delimiter, quote);
+    let mut headers: Option<Arc<Headers>> = None;
+    std::iter::from_fn(move || {
+        if headers.is_none() {
+            match rows.next()? {
+                Ok(names) => headers = Some(Arc::new(Headers::new(names))),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        let row = rows.next()?;
+        Some(row.map(|fields| StringRecord::new(fields, headers.clone())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_stream_records() {
+        let input = "id,email,name\n1,ada@example.com,Ada\n2,bob@example.com\n";
+        let records = stream_records(Cursor::new(input), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].get("email"), Some("ada@example.com"));
+        assert_eq!(records[0].get_index(2), Some("Ada"));
+        assert_eq!(records[0].get("phone"), None);
+        assert_eq!(records[1].get("name"), None);
+        assert_eq!(records[1].get_index(3), None);
+        assert_eq!(records[1].headers().unwrap().index_of("id"), Some(0));
+
+        let headless = StringRecord::new(vec!["x".to_string()], None);
+        assert_eq!(headless.get("x"), None);
+        assert_eq!(headless.get_index(0), Some("x"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Force parsed rows into a rectangular shape
+//!
+//! Downstream consumers usually expect every row to have the same number of
+//! fields. These adapters wrap any row iterator and either enforce that or make
+//! it so.
+
+use crate::csv::dialect::Dialect;
+use crate::csv::easy::stream_valid_csv;
+use crate::errors::{CsvError, Result, Warning};
+use crate::Position;
+use std::io::BufRead;
+
+/// How to treat rows whose width differs from the header's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowShape {
+    /// Fail with `CsvError::Invalid` on any row of the wrong width
+    Strict,
+    /// Pad short rows with empty fields and truncate long ones, with a warning
+    PadTruncate,
+}
+
+/// Rows reshaped to the width of the first row
+///
+/// Created by [`shape_rows`]. Truncated rows are recorded as warnings, available
+/// from [`ShapedRows::warnings`] as the iterator is consumed. The rows can come
+/// from anywhere, so `Position::line` in those warnings and in errors is the
+/// index of the row, counting the header as 0, not a line of the input. Comments,
+/// skipped lines and line breaks inside quoted fields aren't counted.
+pub struct ShapedRows<I> {
+    rows: I,
+    shape: RowShape,
+    width: Option<usize>,
+    /// The index of the next row
+    row: usize,
+    warnings: Vec<Warning>,
+}
+impl<I> ShapedRows<I> {
+    /// The warnings for the rows read so far
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+}
+impl<I: Iterator<Item = Result<Vec<String>>>> Iterator for ShapedRows<I> {
+    type Item = Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = self.row;
+        self.row += 1;
+        let mut row = match self.rows.next()? {
+            Ok(row) => row,
+            Err(error) => return Some(Err(error)),
+        };
+        let width = *self.width.get_or_insert(row.len());
+        if row.len() == width {
+            return Some(Ok(row));
+        }
+        match self.shape {
+            RowShape::Strict => Some(Err(CsvError::invalid_at(
+                line,
+                width,
+                "Row does not have the same number of fields as the header",
+            ))),
+            RowShape::PadTruncate => {
+                if row.len() > width {
+                    self.warnings.push(Warning {
+                        position: Position {
+                            line,
+                            column: width,
+                        },
+                        message: "Row truncated to the width of the header",
+                    });
+                }
+                row.resize(width, String::new());
+                Some(Ok(row))
+            }
+        }
+    }
+}
+
+/// Make every row as wide as the first one, which is taken to be the header
+///
+/// Rows are positioned by their index, as described on [`ShapedRows`].
+pub fn shape_rows<I: Iterator<Item = Result<Vec<String>>>>(
+    rows: I,
+    shape: RowShape,
+) -> ShapedRows<I> {
+    ShapedRows {
+        rows,
+        shape,
+        width: None,
+        row: 0,
+        warnings: Vec::new(),
+    }
+}
+
+/// How to treat rows whose width differs from a fixed target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WidthPolicy {
+    /// Pad short rows with empty fields, and fail on long ones
+    PadWithEmpty,
+    /// Pad short rows with empty fields, and drop the extra fields of long ones
+    Truncate,
+    /// Fail on any row of the wrong width
+    Error,
+}
+
+/// Make every row exactly `width` fields wide
+///
+/// Unlike [`shape_rows`], the width is given up front rather than taken from the
+/// first row. A row the policy can't fix is `CsvError::Invalid` and reading
+/// carries on after it. As in [`ShapedRows`], the error's `Position::line` is the
+/// record index of the row, counting from 0, not a line of the input, and its
+/// column is `width`, the first field past the limit.
+pub fn normalize_width<I: Iterator<Item = Result<Vec<String>>>>(
+    rows: I,
+    width: usize,
+    policy: WidthPolicy,
+) -> impl Iterator<Item = Result<Vec<String>>> {
+    rows.enumerate().map(move |(index, row)| {
+        let mut row = row?;
+        let fixable = match policy {
+            WidthPolicy::PadWithEmpty => row.len() <= width,
+            WidthPolicy::Truncate => true,
+            WidthPolicy::Error => row.len() == width,
+        };
+        if !fixable {
+            return Err(CsvError::invalid_at(
+                index,
+                width,
+                "Row does not have the expected number of fields",
+            ));
+        }
+        row.resize(width, String::new());
+        Ok(row)
+    })
+}
+
+/// Parse a CSV into its header and one vector per column
+///
+/// Rows are read as in [`stream_valid_csv`], with all the options of the
+/// dialect. Each row is scattered into the columns as it is read, so the rows
+/// are never collected and transposed. Rows are shaped to the width of the
+/// header according to `shape`, so ragged rows are an error unless it is
+/// `RowShape::PadTruncate`. The warnings for truncated rows are dropped; use
+/// [`shape_rows`] directly to see them.
+pub fn parse_columnar<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    shape: RowShape,
+) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let mut rows = shape_rows(stream_valid_csv(reader, dialect), shape);
+    let header = match rows.next() {
+        Some(header) => header?,
+        None => return Ok((Vec::new(), Vec::new())),
+    };
+    let mut columns = vec![Vec::new(); header.len()];
+    for row in rows {
+        for (column, field) in columns.iter_mut().zip(row?) {
+            column.push(field);
+        }
+    }
+    Ok((header, columns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::easy::fast_stream_valid_csv;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_shape_rows_pad_truncate() {
+        let input = Cursor::new("a,b,c\n1\n2,3,4,5\n6,7,8\n");
+        let mut rows = shape_rows(
+            fast_stream_valid_csv(input, ',', '"'),
+            RowShape::PadTruncate,
+        );
+        let shaped = rows.by_ref().collect::<Result<Vec<_>>>().unwrap();
+        assert!(shaped.iter().all(|row| row.len() == 3));
+        assert_eq!(
+            shaped[1],
+            vec!["1".to_string(), "".to_string(), "".to_string()]
+        );
+        assert_eq!(
+            shaped[2],
+            vec!["2".to_string(), "3".to_string(), "4".to_string()]
+        );
+        assert_eq!(
+            rows.warnings(),
+            &[Warning {
+                position: Position { line: 2, column: 3 },
+                message: "Row truncated to the width of the header",
+            }]
+        );
+
+        // The row index, not the line, since the second row spans two lines
+        let input = Cursor::new("a,b\n\"x\ny\",1\n1,2,3\n");
+        let mut rows = shape_rows(
+            fast_stream_valid_csv(input, ',', '"'),
+            RowShape::PadTruncate,
+        );
+        rows.by_ref().for_each(drop);
+        assert_eq!(rows.warnings()[0].position, Position { line: 2, column: 2 });
+    }
+
+    #[test]
+    fn test_shape_rows_strict() {
+        let input = Cursor::new("a,b,c\n1\n6,7,8\n");
+        let mut rows = shape_rows(fast_stream_valid_csv(input, ',', '"'), RowShape::Strict);
+        assert!(rows.next().unwrap().is_ok());
+        assert_eq!(
+            rows.next().unwrap().unwrap_err(),
+            CsvError::Invalid(
+                Position { line: 1, column: 3 },
+                "Row does not have the same number of fields as the header"
+            )
+        );
+        assert!(rows.next().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_parse_columnar() {
+        let input = "id,name,score\n1,\"Smith, J\",9\n2,Lee,7\n3,Ng,8\n";
+        let (header, columns) =
+            parse_columnar(Cursor::new(input), &Dialect::default(), RowShape::Strict).unwrap();
+        assert_eq!(header, vec!["id", "name", "score"]);
+
+        let rows = fast_stream_valid_csv(Cursor::new(input), ',', '"')
+            .skip(1)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let transposed = (0..header.len())
+            .map(|column| rows.iter().map(|row| row[column].clone()).collect())
+            .collect::<Vec<Vec<String>>>();
+        assert_eq!(columns, transposed);
+    }
+
+    #[test]
+    fn test_parse_columnar_ragged() {
+        let input = "a,b\n1\n2,3,4\n";
+        assert!(parse_columnar(Cursor::new(input), &Dialect::default(), RowShape::Strict).is_err());
+        let (_, columns) = parse_columnar(
+            Cursor::new(input),
+            &Dialect::default(),
+            RowShape::PadTruncate,
+        )
+        .unwrap();
+        assert_eq!(columns, vec![vec!["1", "2"], vec!["", "3"]]);
+
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let input = "# c\na,b\n1,2\n";
+        let (header, columns) =
+            parse_columnar(Cursor::new(input), &dialect, RowShape::Strict).unwrap();
+        assert_eq!(header, vec!["a", "b"]);
+        assert_eq!(columns, vec![vec!["1"], vec!["2"]]);
+    }
+
+    #[test]
+    fn test_normalize_width() {
+        let input = "1\n2,3\n4,5,6\n";
+        let normalize = |policy| {
+            normalize_width(
+                fast_stream_valid_csv(Cursor::new(input), ',', '"'),
+                2,
+                policy,
+            )
+            .collect::<Vec<_>>()
+        };
+        let row = |fields: &[&str]| Ok(fields.iter().map(|f| f.to_string()).collect());
+        let error = |line| {
+            Err(CsvError::Invalid(
+                Position { line, column: 2 },
+                "Row does not have the expected number of fields",
+            ))
+        };
+        assert_eq!(
+            normalize(WidthPolicy::Truncate),
+            vec![row(&["1", ""]), row(&["2", "3"]), row(&["4", "5"])]
+        );
+        assert_eq!(
+            normalize(WidthPolicy::PadWithEmpty),
+            vec![row(&["1", ""]), row(&["2", "3"]), error(2)]
+        );
+        assert_eq!(
+            normalize(WidthPolicy::Error),
+            vec![error(0), row(&["2", "3"]), error(2)]
+        );
+
+        // The record index, not the line, since the first record spans two lines
+        let rows = fast_stream_valid_csv(Cursor::new("\"a\nb\",c\nd\n"), ',', '"');
+        let normalized = normalize_width(rows, 2, WidthPolicy::Error).collect::<Vec<_>>();
+        assert_eq!(normalized, vec![row(&["a\nb", "c"]), error(1)]);
+    }
+}
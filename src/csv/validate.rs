@@ -0,0 +1,299 @@
+//! Check CSV files against the rules of a dialect without repairing them
+//!
+//! These validators report the first problem they find as a `CsvError` with the
+//! `Position` of the offending byte, so they can point a user at the exact spot
+//! that needs fixing.
+
+use crate::csv::dialect::Dialect;
+use crate::csv::easy::{dialect_byte, stream_valid_csv_with_lines};
+use crate::errors::{CsvError, Result};
+use std::io::BufRead;
+
+/// Check that every quote inside a quoted field is escaped by doubling it
+///
+/// A quote inside a quoted field must either be followed by another quote, making
+/// a literal quote, or close the field by being followed by a delimiter, a newline,
+/// or the end of the input. Anything else is a lone quote that most parsers will
+/// misread, and its position is reported.
+pub fn validate_quote_escaping(raw: &[u8], delimiter: u8, quote: u8) -> Result<()> {
+    let mut line = 0;
+    let mut column = 0;
+    let mut at_field_start = true;
+    let mut within_quotes = false;
+    let mut bytes = raw.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        let next = bytes.peek().map(|&&next| next);
+        if within_quotes && byte == quote {
+            if next == Some(quote) {
+                // A doubled quote is a literal quote
+                bytes.next();
+                column += 1;
+            } else if next.is_none()
+                || next == Some(delimiter)
+                || next == Some(b'\n')
+                || next == Some(b'\r')
+            {
+                within_quotes = false;
+            } else {
+                return Err(CsvError::invalid_at(
+                    line,
+                    column,
+                    "Unescaped quote inside a quoted field",
+                ));
+            }
+        } else if at_field_start && byte == quote {
+            within_quotes = true;
+        } else if byte == b'\n' {
+            line += 1;
+            column = 0;
+            at_field_start = !within_quotes;
+            continue;
+        }
+        at_field_start = !within_quotes && byte == delimiter;
+        column += 1;
+    }
+    Ok(())
+}
+
+/// Check that every row has one of the allowed numbers of fields
+///
+/// This is for files mixing a few known record types, where no single width is
+/// right but any other width is a mistake. Unlike the repair paths nothing is
+/// guessed: the first row with a count that isn't allowed is reported at the
+/// line it starts on, counting comments, skipped empty lines and line breaks
+/// inside quoted fields. No one field is at fault, so the column is 0.
+pub fn validate_column_counts<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    allowed: &[usize],
+) -> Result<()> {
+    for row in stream_valid_csv_with_lines(reader, dialect) {
+        let (lines, row) = row?;
+        if !allowed.contains(&row.len()) {
+            return Err(CsvError::invalid_at(
+                lines.start,
+                0,
+                "Row has a number of fields that is not allowed",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the fields in some columns are always quoted
+///
+/// A quote only opens a field at its very start, so a field like `a"b"` is not
+/// quoted. An empty field isn't quoted either, unless it is written `""`. The
+/// first unquoted field in one of the `required_quoted` columns is reported at
+/// its first character. Lines are counted from zero, including line breaks
+/// inside quoted fields. The bytes are scanned directly, so a dialect whose
+/// delimiter or quote isn't ASCII is `CsvError::Invalid`.
+pub fn validate_quoting<R: BufRead>(
+    reader: R,
+    dialect: &Dialect,
+    required_quoted: &[usize],
+) -> Result<()> {
+    let delimiter = dialect_byte(dialect.delimiter)?;
+    let quote = dialect_byte(dialect.quote)?;
+    let mut line = 0;
+    let mut column = 0;
+    let mut field = 0;
+    let mut at_field_start = true;
+    let mut field_quoted = false;
+    let mut within_quotes = false;
+    // Where the current field started, if it is in a required column and unquoted
+    let mut unquoted_start = None;
+    for byte in reader.bytes() {
+        let byte = byte?;
+        if at_field_start {
+            at_field_start = false;
+            field_quoted = byte == quote;
+            within_quotes = field_quoted;
+            if !field_quoted && required_quoted.contains(&field) {
+                unquoted_start = Some((line, column));
+            }
+        } else if field_quoted && byte == quote {
+            // A doubled quote toggles twice, so it leaves the field open
+            within_quotes = !within_quotes;
+        }
+        if !within_quotes && (byte == delimiter || byte == b'\n') {
+            if let Some((line, column)) = unquoted_start {
+                return Err(CsvError::invalid_at(
+                    line,
+                    column,
+                    "Field in a column that must be quoted is not quoted",
+                ));
+            }
+            at_field_start = true;
+            field = if byte == b'\n' { 0 } else { field + 1 };
+        }
+        if byte == b'\n' {
+            line += 1;
+            column = 0;
+        } else {
+            column += 1;
+        }
+    }
+    // A line break at the very end of the input doesn't start another field, but
+    // a delimiter there leaves an empty one
+    if at_field_start && field > 0 && required_quoted.contains(&field) {
+        unquoted_start = Some((line, column));
+    }
+    match unquoted_start {
+        Some((line, column)) => Err(CsvError::invalid_at(
+            line,
+            column,
+            "Field in a column that must be quoted is not quoted",
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check that a file has as many records as it says it does
+///
+/// This catches truncated downloads of files that declare their length, as in a
+/// `ROWS: 1000` header or footer. Every record counts, including any header row,
+/// but comment lines don't, and nor do empty lines if the dialect skips them, so
+/// a declaration written as a comment isn't counted itself. If there are too few
+/// records, the error is at the line after the last one; if there are too many,
+/// it is at the line where the first record past the declared count starts.
+pub fn verify_declared_count(raw: &[u8], dialect: &Dialect, declared: usize) -> Result<()> {
+    let mut count = 0;
+    let mut end = 0;
+    for record in stream_valid_csv_with_lines(raw, dialect) {
+        let (lines, _) = record?;
+        if count == declared {
+            return Err(CsvError::invalid_at(
+                lines.start,
+                0,
+                "File has more records than it declares",
+            ));
+        }
+        count += 1;
+        end = lines.end;
+    }
+    if count < declared {
+        return Err(CsvError::invalid_at(
+            end,
+            0,
+            "File has fewer records than it declares",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Position;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_validate_quote_escaping() {
+        assert_eq!(
+            validate_quote_escaping(b"\"a\"\"b\",c\n\"d\ne\",f\n", b',', b'"'),
+            Ok(())
+        );
+        assert_eq!(
+            validate_quote_escaping(b"\"a\"b\"", b',', b'"'),
+            Err(CsvError::Invalid(
+                Position { line: 0, column: 2 },
+                "Unescaped quote inside a quoted field"
+            ))
+        );
+        assert_eq!(
+            validate_quote_escaping(b"x,y\nz,\"a \"b\" c\"\n", b',', b'"'),
+            Err(CsvError::Invalid(
+                Position { line: 1, column: 5 },
+                "Unescaped quote inside a quoted field"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_validate_column_counts() {
+        let dialect = Dialect::default();
+        let valid = "H,1,2,3\nD,1,2,3,4,5,6\nH,1,2,3\n";
+        assert_eq!(
+            validate_column_counts(Cursor::new(valid), &dialect, &[4, 7]),
+            Ok(())
+        );
+        let invalid = "H,1,2,3\nD,1,2,3,4\n";
+        assert_eq!(
+            validate_column_counts(Cursor::new(invalid), &dialect, &[4, 7]),
+            Err(CsvError::Invalid(
+                Position { line: 1, column: 0 },
+                "Row has a number of fields that is not allowed"
+            ))
+        );
+        let invalid = "H,1,2,3\n\"x\ny\",1,2,3\nD,1,2,3,4\n";
+        assert_eq!(
+            validate_column_counts(Cursor::new(invalid), &dialect, &[4, 7]),
+            Err(CsvError::Invalid(
+                Position { line: 3, column: 0 },
+                "Row has a number of fields that is not allowed"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_validate_quoting() {
+        let dialect = Dialect::default();
+        let valid = "id,\"note\"\n1,\"a, b\"\n2,\"two\nlines\"\n3,\"\"\n";
+        assert_eq!(validate_quoting(Cursor::new(valid), &dialect, &[1]), Ok(()));
+        assert_eq!(
+            validate_quoting(Cursor::new(valid), &dialect, &[0]),
+            Err(CsvError::Invalid(
+                Position { line: 0, column: 0 },
+                "Field in a column that must be quoted is not quoted"
+            ))
+        );
+        let invalid = "id,\"note\"\n1,\"x\"\n2,plain\n";
+        assert_eq!(
+            validate_quoting(Cursor::new(invalid), &dialect, &[1]),
+            Err(CsvError::Invalid(
+                Position { line: 2, column: 2 },
+                "Field in a column that must be quoted is not quoted"
+            ))
+        );
+        assert!(validate_quoting(Cursor::new("\"a\",b"), &dialect, &[1]).is_err());
+        assert!(validate_quoting(Cursor::new("\"a\","), &dialect, &[1]).is_err());
+        assert!(validate_quoting(Cursor::new("\"a\"\n"), &dialect, &[0]).is_ok());
+        // U+012C would be read as the byte 0x2C, a comma
+        let dialect = Dialect {
+            delimiter: '\u{012C}',
+            ..Dialect::default()
+        };
+        assert_eq!(
+            validate_quoting(Cursor::new(valid), &dialect, &[1]),
+            Err(CsvError::Invalid(
+                Position { line: 0, column: 0 },
+                "Dialect character is not ASCII"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_verify_declared_count() {
+        let dialect = Dialect {
+            comment: Some('#'),
+            ..Dialect::default()
+        };
+        let raw = b"# ROWS: 3\nid,name\n1,ada\n2,\"bob\nsmith\"\n";
+        assert_eq!(verify_declared_count(raw, &dialect, 3), Ok(()));
+        assert_eq!(
+            verify_declared_count(raw, &dialect, 5),
+            Err(CsvError::Invalid(
+                Position { line: 5, column: 0 },
+                "File has fewer records than it declares"
+            ))
+        );
+        assert_eq!(
+            verify_declared_count(raw, &dialect, 2),
+            Err(CsvError::Invalid(
+                Position { line: 3, column: 0 },
+                "File has more records than it declares"
+            ))
+        );
+    }
+}
@@ -0,0 +1,350 @@
+//! Write CSV that the readers in this crate read back unchanged
+
+use crate::csv::detect::Encoding;
+use crate::errors::{CsvError, Result};
+use std::borrow::Cow;
+use std::io::Write;
+
+/// When fields are surrounded by quotes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotingStyle {
+    /// Only fields containing the delimiter, the quote, `\r` or `\n`
+    Necessary,
+    /// Every field
+    Always,
+    /// No field, so the caller has to make sure none of them need it
+    Never,
+}
+
+/// What to do with a field that has a newline in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddedNewlinePolicy {
+    /// Keep the newline, quoting the field as any other that needs it
+    Quote,
+    /// Write this character in place of each `\r\n`, `\r` or `\n`
+    Replace(char),
+    /// Fail with `CsvError::Invalid` at the field
+    Error,
+}
+
+/// What to do with a field that has the delimiter in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DelimiterInFieldPolicy {
+    /// Keep the delimiter, quoting the field as any other that needs it
+    Quote,
+    /// Write this string in place of each delimiter, for readers that can't
+    /// take quotes
+    Replace(String),
+}
+
+/// Writes records of CSV, one at a time
+///
+/// Output is buffered by whatever `W` is and is not flushed when the writer is
+/// dropped, so call [`Writer::flush`] once the last record is written.
+pub struct Writer<W> {
+    writer: W,
+    delimiter: u8,
+    quote: u8,
+    quoting: QuotingStyle,
+    newlines: EmbeddedNewlinePolicy,
+    delimiters: DelimiterInFieldPolicy,
+    write_bom: bool,
+    line: usize,
+}
+impl<W: Write> Writer<W> {
+    /// Write RFC 4180 CSV: comma separated, double quoted only where necessary
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            delimiter: b',',
+            quote: b'"',
+            quoting: QuotingStyle::Necessary,
+            newlines: EmbeddedNewlinePolicy::Quote,
+            delimiters: DelimiterInFieldPolicy::Quote,
+            write_bom: false,
+            line: 0,
+        }
+    }
+
+    /// Separate fields with a different byte
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Quote fields with a different byte
+    pub fn with_quote(mut self, quote: u8) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    /// Choose which fields are quoted
+    pub fn with_quoting(mut self, quoting: QuotingStyle) -> Self {
+        self.quoting = quoting;
+        self
+    }
+
+    /// Choose what happens to newlines inside fields
+    ///
+    /// Some readers can't take a newline in a field even if it is quoted.
+    pub fn with_embedded_newlines(mut self, newlines: EmbeddedNewlinePolicy) -> Self {
+        self.newlines = newlines;
+        self
+    }
+
+    /// Choose what happens to delimiters inside fields
+    ///
+    /// Replacing them means a field never needs quoting for its delimiters, so
+    /// with [`QuotingStyle::Never`] the output can still be split correctly.
+    pub fn with_delimiter_in_field(mut self, delimiters: DelimiterInFieldPolicy) -> Self {
+        self.delimiters = delimiters;
+        self
+    }
+
+    /// Choose whether the output starts with a UTF-8 byte order mark
+    ///
+    /// Excel needs one to read the file as UTF-8. The mark is written just before
+    /// the first record, so nothing is written if there are no records.
+    pub fn with_bom(mut self, write_bom: bool) -> Self {
+        self.write_bom = write_bom;
+        self
+    }
+
+    /// Write one record, ending in `\n`
+    ///
+    /// Quotes inside a quoted field are doubled. A record of a single empty field
+    /// is quoted unless quoting is off, so it isn't read back as an empty line.
+    /// Newlines in a field are handled as set by [`Writer::with_embedded_newlines`],
+    /// and under [`EmbeddedNewlinePolicy::Error`] the error is at the record's
+    /// line and the field's column, with none of the record written. Delimiters
+    /// are handled as set by [`Writer::with_delimiter_in_field`].
+    pub fn write_record<I: IntoIterator<Item = impl AsRef<[u8]>>>(
+        &mut self,
+        fields: I,
+    ) -> Result<()> {
+        let fields = fields.into_iter().collect::<Vec<_>>();
+        let fields = fields
+            .iter()
+            .enumerate()
+            .map(|(column, field)| {
+                self.replace_newlines(field.as_ref(), column)
+                    .map(|field| self.replace_delimiters(field))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if self.write_bom && self.line == 0 {
+            self.writer.write_all(Encoding::Utf8.bom())?;
+        }
+        let mut fields = fields.iter().peekable();
+        let mut first = true;
+        while let Some(field) = fields.next() {
+            let field = field.as_ref();
+            if !first {
+                self.writer.write_all(&[self.delimiter])?;
+            }
+            let lone_empty = first && field.is_empty() && fields.peek().is_none();
+            first = false;
+            let quoted = match self.quoting {
+                QuotingStyle::Necessary => lone_empty || self.needs_quotes(field),
+                QuotingStyle::Always => true,
+                QuotingStyle::Never => false,
+            };
+            if !quoted {
+                self.writer.write_all(field)?;
+                continue;
+            }
+            self.writer.write_all(&[self.quote])?;
+            for chunk in field.split_inclusive(|&byte| byte == self.quote) {
+                self.writer.write_all(chunk)?;
+                if chunk.last() == Some(&self.quote) {
+                    self.writer.write_all(&[self.quote])?;
+                }
+            }
+            self.writer.write_all(&[self.quote])?;
+        }
+        self.writer.write_all(b"\n")?;
+        self.line += 1;
+        Ok(())
+    }
+
+    /// Flush everything written so far to the underlying writer
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Unwrap the underlying writer, without flushing it
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Apply the embedded newline policy to one field
+    fn replace_newlines<'t>(&self, field: &'t [u8], column: usize) -> Result<Cow<'t, [u8]>> {
+        let has_newline = field.iter().any(|&byte| byte == b'\r' || byte == b'\n');
+        match self.newlines {
+            _ if !has_newline => Ok(Cow::Borrowed(field)),
+            EmbeddedNewlinePolicy::Quote => Ok(Cow::Borrowed(field)),
+            EmbeddedNewlinePolicy::Error => Err(CsvError::invalid_at(
+                self.line,
+                column,
+                "Field contains a newline",
+            )),
+            EmbeddedNewlinePolicy::Replace(replacement) => {
+                let mut encoded = [0; 4];
+                let replacement = replacement.encode_utf8(&mut encoded).as_bytes();
+                let mut replaced = Vec::with_capacity(field.len());
+                let mut bytes = field.iter().peekable();
+                while let Some(&byte) = bytes.next() {
+                    match byte {
+                        b'\r' => {
+                            bytes.next_if_eq(&&b'\n');
+                            replaced.extend_from_slice(replacement);
+                        }
+                        b'\n' => replaced.extend_from_slice(replacement),
+                        _ => replaced.push(byte),
+                    }
+                }
+                Ok(Cow::Owned(replaced))
+            }
+        }
+    }
+
+    /// Apply the delimiter policy to one field
+    fn replace_delimiters<'t>(&self, field: Cow<'t, [u8]>) -> Cow<'t, [u8]> {
+        let DelimiterInFieldPolicy::Replace(replacement) = &self.delimiters else {
+            return field;
+        };
+        if !field.contains(&self.delimiter) {
+            return field;
+        }
+        let mut replaced = Vec::with_capacity(field.len());
+        for &byte in field.iter() {
+            if byte == self.delimiter {
+                replaced.extend_from_slice(replacement.as_bytes());
+            } else {
+                replaced.push(byte);
+            }
+        }
+        Cow::Owned(replaced)
+    }
+
+    /// Whether a field has to be quoted to be read back the same
+    fn needs_quotes(&self, field: &[u8]) -> bool {
+        field.iter().any(|&byte| {
+            byte == self.delimiter || byte == self.quote || byte == b'\r' || byte == b'\n'
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv::easy::fast_stream_valid_csv;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_record() {
+        let rows = vec![
+            vec!["id", "note"],
+            vec!["1", "say \"hi\""],
+            vec!["2", "a,b\r\nc"],
+            vec![""],
+        ];
+        let mut writer = Writer::new(Vec::new());
+        for row in &rows {
+            writer.write_record(row).unwrap();
+        }
+        writer.flush().unwrap();
+        let output = writer.into_inner();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "id,note\n1,\"say \"\"hi\"\"\"\n2,\"a,b\r\nc\"\n\"\"\n"
+        );
+        let read_back = fast_stream_valid_csv(Cursor::new(output), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read_back, rows);
+    }
+
+    #[test]
+    fn test_write_record_quoting_styles() {
+        let write = |writer: Writer<Vec<u8>>| {
+            let mut writer = writer.with_delimiter(b';').with_quote(b'\'');
+            writer.write_record(["a;b", "it's", "c"]).unwrap();
+            String::from_utf8(writer.into_inner()).unwrap()
+        };
+        assert_eq!(write(Writer::new(Vec::new())), "'a;b';'it''s';c\n");
+        assert_eq!(
+            write(Writer::new(Vec::new()).with_quoting(QuotingStyle::Always)),
+            "'a;b';'it''s';'c'\n"
+        );
+        assert_eq!(
+            write(Writer::new(Vec::new()).with_quoting(QuotingStyle::Never)),
+            "a;b;it's;c\n"
+        );
+    }
+
+    #[test]
+    fn test_write_record_embedded_newlines() {
+        let write = |newlines| {
+            let mut writer = Writer::new(Vec::new()).with_embedded_newlines(newlines);
+            writer.write_record(["id", "note"]).unwrap();
+            writer
+                .write_record(["1", "two\nlines\r\nhere"])
+                .map(|()| String::from_utf8(writer.into_inner()).unwrap())
+        };
+        assert_eq!(
+            write(EmbeddedNewlinePolicy::Quote),
+            Ok("id,note\n1,\"two\nlines\r\nhere\"\n".to_string())
+        );
+        assert_eq!(
+            write(EmbeddedNewlinePolicy::Replace(' ')),
+            Ok("id,note\n1,two lines here\n".to_string())
+        );
+        assert_eq!(
+            write(EmbeddedNewlinePolicy::Error),
+            Err(CsvError::invalid_at(1, 1, "Field contains a newline"))
+        );
+        let mut writer =
+            Writer::new(Vec::new()).with_embedded_newlines(EmbeddedNewlinePolicy::Error);
+        assert!(writer.write_record(["1", "a\nb"]).is_err());
+        assert!(writer.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_write_record_delimiter_in_field() {
+        let write = |delimiters| {
+            let mut writer = Writer::new(Vec::new()).with_delimiter_in_field(delimiters);
+            writer.write_record(["1", "Smith, J", "x"]).unwrap();
+            String::from_utf8(writer.into_inner()).unwrap()
+        };
+        assert_eq!(write(DelimiterInFieldPolicy::Quote), "1,\"Smith, J\",x\n");
+        assert_eq!(
+            write(DelimiterInFieldPolicy::Replace(" ".to_string())),
+            "1,Smith  J,x\n"
+        );
+        let mut writer = Writer::new(Vec::new())
+            .with_delimiter(b';')
+            .with_quoting(QuotingStyle::Never)
+            .with_delimiter_in_field(DelimiterInFieldPolicy::Replace("<semi>".to_string()));
+        writer.write_record(["a;b", "c"]).unwrap();
+        assert_eq!(writer.into_inner(), b"a<semi>b;c\n");
+    }
+
+    #[test]
+    fn test_write_record_bom() {
+        let write = |write_bom| {
+            let mut writer = Writer::new(Vec::new()).with_bom(write_bom);
+            writer.write_record(["id", "name"]).unwrap();
+            writer.write_record(["1", "Zoë"]).unwrap();
+            writer.into_inner()
+        };
+        let with_bom = write(true);
+        assert!(with_bom.starts_with(b"\xEF\xBB\xBFid,name\n"));
+        assert_eq!(&with_bom[3..], write(false));
+        assert_eq!(write(false), "id,name\n1,Zoë\n".as_bytes());
+        let read_back = fast_stream_valid_csv(Cursor::new(with_bom), ',', '"')
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read_back, vec![vec!["id", "name"], vec!["1", "Zoë"]]);
+    }
+}
@@ -15,6 +15,18 @@ pub enum CsvError {
     // Add more custom variants as needed
 }
 
+impl CsvError {
+    /// An invalid input error at a line and column
+    pub fn invalid_at(line: usize, column: usize, message: &'static str) -> Self {
+        CsvError::Invalid(Position { line, column }, message)
+    }
+
+    /// An ambiguous parse error at a line and column
+    pub fn ambiguous_at(line: usize, column: usize, message: &'static str) -> Self {
+        CsvError::Ambiguity(Position { line, column }, message)
+    }
+}
+
 impl From<io::Error> for CsvError {
     fn from(error: io::Error) -> Self {
         CsvError::Io(error.to_string())
@@ -22,3 +34,31 @@ impl From<io::Error> for CsvError {
 }
 
 pub type Result<T> = std::result::Result<T, CsvError>;
+
+/// A problem that was worked around instead of failing the parse
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub position: Position,
+    pub message: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_at() {
+        assert_eq!(
+            CsvError::invalid_at(3, 7, "Bad field"),
+            CsvError::Invalid(Position { line: 3, column: 7 }, "Bad field")
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_at() {
+        assert_eq!(
+            CsvError::ambiguous_at(0, 2, "Stray quote"),
+            CsvError::Ambiguity(Position { line: 0, column: 2 }, "Stray quote")
+        );
+    }
+}
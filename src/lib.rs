@@ -1,7 +1,7 @@
-mod csv;
-mod errors;
+pub mod csv;
+pub mod errors;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Position {
     line: usize,
     column: usize,